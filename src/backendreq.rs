@@ -0,0 +1,123 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'BackendReq' module serves the vhost-user backend request channel
+//! (VHOST_USER_PROTOCOL_F_BACKEND_REQ), through which a backend pushes messages to the frontend.
+//!
+//! Each device with the channel set up runs a thread serving the requests of its backend:
+//!
+//! - `CONFIG_CHANGE_MSG` is translated into a configuration change interrupt towards the guest.
+//! - `FS_MAP` is refused, as the devices have no shared memory region: the guest mappings of the
+//!   Bao shared memory cannot be changed from the frontend to map file ranges into a virtio-fs
+//!   cache window.
+//! - Any other request is refused, with a failure reply if the backend asked for one, so that
+//!   the backend never blocks waiting for the frontend.
+
+use super::device::BaoDevice;
+use log::{debug, warn};
+use std::io::{Error as IoError, Result as IoResult};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Weak};
+use vhost::vhost_user::message::VhostUserFSBackendMsg;
+use vhost::vhost_user::{Error as VhostUserError, FrontendReqHandler, VhostUserFrontendReqHandler};
+
+/// Struct representing the handler of the backend requests of a device.
+///
+/// # Attributes
+///
+/// * `dev` - The device, which owns the thread running the handler.
+pub struct BaoBackendReqHandler {
+    dev: Weak<BaoDevice>,
+}
+
+impl BaoBackendReqHandler {
+    /// Constructor function for BaoBackendReqHandler.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - The device.
+    ///
+    /// # Return
+    ///
+    /// * `BaoBackendReqHandler` - The BaoBackendReqHandler object.
+    pub fn new(dev: Weak<BaoDevice>) -> Self {
+        Self { dev }
+    }
+
+    /// Returns the device, unless it is gone.
+    ///
+    /// # Return
+    ///
+    /// * `IoResult<Arc<BaoDevice>>` - The device, or an Error if it is gone.
+    fn dev(&self) -> IoResult<Arc<BaoDevice>> {
+        self.dev
+            .upgrade()
+            .ok_or(IoError::from_raw_os_error(libc::ENODEV))
+    }
+}
+
+impl VhostUserFrontendReqHandler for BaoBackendReqHandler {
+    /// Handles a configuration change notification of the backend.
+    ///
+    /// # Return
+    ///
+    /// * `IoResult<u64>` - Ok(0) once the driver was notified, or an Error if the device is gone.
+    fn handle_config_change(&self) -> IoResult<u64> {
+        let dev = self.dev()?;
+
+        debug!(target: &dev.target, "backend configuration change");
+        dev.notify_config_change();
+
+        Ok(0)
+    }
+
+    /// Refuses to map file ranges into the virtio-fs cache window, which the device does not
+    /// have.
+    ///
+    /// # Arguments
+    ///
+    /// * `fs` - The ranges, unused entries having a zero length.
+    /// * `fd` - The file descriptor of the file.
+    ///
+    /// # Return
+    ///
+    /// * `IoResult<u64>` - An Error, as the ranges cannot be mapped.
+    fn fs_backend_map(&self, _fs: &VhostUserFSBackendMsg, _fd: &dyn AsRawFd) -> IoResult<u64> {
+        let dev = self.dev()?;
+
+        warn!(target: &dev.target, "backend map refused, DAX is not supported");
+        Err(IoError::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+}
+
+/// Serves the backend requests until the channel is shut down or the backend hangs up.
+///
+/// # Arguments
+///
+/// * `channel` - The frontend end of the backend request channel.
+/// * `target` - Log target of the device.
+pub fn serve(mut channel: FrontendReqHandler<BaoBackendReqHandler>, target: String) {
+    loop {
+        match channel.handle_request() {
+            Ok(_) => (),
+            // The request was refused and the backend got a failure reply, if it asked for one
+            Err(VhostUserError::ReqHandlerError(err)) => {
+                debug!(target: &target, "backend request refused: {}", err)
+            }
+            Err(VhostUserError::InvalidMessage) => {
+                debug!(target: &target, "unsupported backend request refused")
+            }
+            Err(
+                VhostUserError::Disconnected
+                | VhostUserError::PartialMessage
+                | VhostUserError::SocketBroken(_)
+                | VhostUserError::SocketError(_),
+            ) => break,
+            Err(err) => warn!(target: &target, "malformed backend request: {}", err),
+        }
+    }
+
+    debug!(target: &target, "backend request channel closed");
+}
@@ -0,0 +1,569 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Config' module holds the per-device options of the frontend, which complement the
+//! device description parsed by `bao_sys::utils::parse_arguments`.
+//!
+//! Options are written as a comma-separated list of `key=value` pairs, e.g.
+//! `needs_reset_on_error=on`. They can be given:
+//!
+//! - On the `add_device` command of the control socket, through the `options` argument.
+//! - In the file pointed to by the `BAO_VHOST_DEVICE_OPTIONS` environment variable, holding one
+//!   `<guest_id> <dev_addr> <options>` line per device. Empty lines and lines starting with `#`
+//!   are ignored.
+
+use bao_sys::defines::VIRTIO_MMIO_IO_SIZE;
+use std::collections::HashMap;
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
+use virtio_bindings::virtio_config::{
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_PACKED, VIRTIO_F_RING_RESET, VIRTIO_F_VERSION_1,
+};
+use virtio_bindings::virtio_mmio::VIRTIO_MMIO_CONFIG;
+
+/// Environment variable holding the path to the device options file.
+pub const DEVICE_OPTIONS_ENV: &str = "BAO_VHOST_DEVICE_OPTIONS";
+
+/// vhost-user protocol features the frontend knows how to make use of, by option name.
+const PROTOCOL_FEATURES: &[(&str, VhostUserProtocolFeatures)] = &[
+    ("mq", VhostUserProtocolFeatures::MQ),
+    ("reply_ack", VhostUserProtocolFeatures::REPLY_ACK),
+    ("config", VhostUserProtocolFeatures::CONFIG),
+    ("inflight_shmem", VhostUserProtocolFeatures::INFLIGHT_SHMEM),
+    ("backend_req", VhostUserProtocolFeatures::BACKEND_REQ),
+    ("reset_device", VhostUserProtocolFeatures::RESET_DEVICE),
+];
+
+/// Transport features whose policy can be configured, by option name.
+const TRANSPORT_FEATURES: &[(&str, u32)] = &[
+    ("iommu_platform", VIRTIO_F_IOMMU_PLATFORM),
+    ("ring_reset", VIRTIO_F_RING_RESET),
+    ("ring_packed", VIRTIO_F_RING_PACKED),
+];
+
+/// Parses a number in decimal or hexadecimal (`0x` prefix) notation.
+///
+/// # Arguments
+///
+/// * `value` - The string to be parsed.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The number, or None if the string is not a valid number.
+pub fn parse_u64(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parses a boolean option value.
+///
+/// # Arguments
+///
+/// * `key` - The option name, used in the error message.
+/// * `value` - The string to be parsed.
+///
+/// # Returns
+///
+/// * `Result<bool, String>` - The boolean, or a message if the value is not a valid boolean.
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "1" => Ok(true),
+        "off" | "false" | "0" => Ok(false),
+        _ => Err(format!("invalid boolean '{}' for '{}'", value, key)),
+    }
+}
+
+/// Parses a `+`-separated list of vhost-user protocol feature names.
+///
+/// # Arguments
+///
+/// * `value` - The string to be parsed, e.g. `mq+config`.
+///
+/// # Returns
+///
+/// * `Result<VhostUserProtocolFeatures, String>` - The protocol features, or a message naming the unknown feature.
+fn parse_protocol_features(value: &str) -> Result<VhostUserProtocolFeatures, String> {
+    value.split('+').filter(|name| !name.is_empty()).try_fold(
+        VhostUserProtocolFeatures::empty(),
+        |features, name| {
+            PROTOCOL_FEATURES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, feature)| features | *feature)
+                .ok_or(format!("unknown protocol feature '{}'", name))
+        },
+    )
+}
+
+/// Returns the names of a set of vhost-user protocol features, in the format of the
+/// `protocol_features` option. Features without a name are shown as a hexadecimal mask.
+///
+/// # Arguments
+///
+/// * `features` - The protocol features.
+///
+/// # Returns
+///
+/// * `String` - The `+`-separated names, or `none` if the set is empty.
+pub fn protocol_feature_names(features: VhostUserProtocolFeatures) -> String {
+    let mut names: Vec<String> = PROTOCOL_FEATURES
+        .iter()
+        .filter(|(_, feature)| features.contains(*feature))
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    // Name the features the frontend does not know about by their bits
+    let unknown = PROTOCOL_FEATURES
+        .iter()
+        .fold(features, |features, (_, feature)| features - *feature);
+    if !unknown.is_empty() {
+        names.push(format!("0x{:x}", unknown.bits()));
+    }
+
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join("+")
+    }
+}
+
+/// Represents the policy applied to the transport features of a device. A feature is either
+/// forced (offered, and the driver must accept it), offered (the driver may decline it) or
+/// masked (never offered).
+///
+/// # Attributes
+///
+/// * `forced` - The features the driver must accept whenever they are offered.
+/// * `masked` - The features never offered to the driver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportPolicy {
+    pub forced: u64,
+    pub masked: u64,
+}
+
+impl Default for TransportPolicy {
+    fn default() -> Self {
+        // Drivers must use the DMA API by default, so that their buffers are placed in the
+        // memory shared with the backend
+        Self {
+            forced: 1 << VIRTIO_F_IOMMU_PLATFORM,
+            masked: 0,
+        }
+    }
+}
+
+/// Parses a `+`-separated list of `<feature>:<policy>` transport feature policies, applied on
+/// top of the default policy.
+///
+/// # Arguments
+///
+/// * `value` - The string to be parsed, e.g. `iommu_platform:offer+ring_reset:mask`.
+///
+/// # Returns
+///
+/// * `Result<TransportPolicy, String>` - The policy, or a message naming the invalid entry.
+fn parse_transport_features(value: &str) -> Result<TransportPolicy, String> {
+    value.split('+').filter(|entry| !entry.is_empty()).try_fold(
+        TransportPolicy::default(),
+        |mut policy, entry| {
+            let (name, action) = entry
+                .split_once(':')
+                .ok_or(format!("malformed transport feature policy '{}'", entry))?;
+            let feature = TRANSPORT_FEATURES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, bit)| 1u64 << bit)
+                .ok_or(format!("unknown transport feature '{}'", name))?;
+
+            policy.forced &= !feature;
+            policy.masked &= !feature;
+            match action {
+                "force" => policy.forced |= feature,
+                "offer" => (),
+                "mask" => policy.masked |= feature,
+                _ => {
+                    return Err(format!(
+                        "invalid policy '{}' for transport feature '{}'",
+                        action, name
+                    ))
+                }
+            }
+
+            Ok(policy)
+        },
+    )
+}
+
+/// Returns the names of a set of transport features, as used by the `transport_features`
+/// option. Features without a name are shown as a hexadecimal mask.
+///
+/// # Arguments
+///
+/// * `features` - The features.
+///
+/// # Returns
+///
+/// * `String` - The `+`-separated names, or `none` if the set is empty.
+pub fn transport_feature_names(features: u64) -> String {
+    let mut names: Vec<String> = TRANSPORT_FEATURES
+        .iter()
+        .filter(|(_, bit)| features & (1 << bit) != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    // Name the features the frontend does not know about by their bits
+    let unknown = TRANSPORT_FEATURES
+        .iter()
+        .fold(features, |features, (_, bit)| features & !(1 << bit));
+    if unknown != 0 {
+        names.push(format!("0x{:x}", unknown));
+    }
+
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join("+")
+    }
+}
+
+/// Represents the per-device options.
+///
+/// # Attributes
+///
+/// * `needs_reset_on_error` - Whether a faulty access moves the device to DEVICE_NEEDS_RESET.
+/// * `mmio_size` - The size of the device MMIO window.
+/// * `reconnect_retries` - The number of attempts to reconnect to a backend that hung up
+///   (0 moves the device straight to DEVICE_NEEDS_RESET).
+/// * `reconnect_backoff` - The delay before the first reconnection attempt, in milliseconds.
+/// * `reconnect_backoff_max` - The upper bound of the delay between attempts, in milliseconds.
+/// * `protocol_features` - The vhost-user protocol features that may be negotiated with the backend.
+/// * `irqfd` - Whether interrupts are injected through an irqfd, rather than through
+///   `notify_guest` (used anyway if the kernel module does not support irqfds).
+/// * `ioeventfd` - Whether QUEUE_NOTIFY writes signal the kick eventfds in the kernel, rather
+///   than in the frontend (done anyway if the kernel module does not support ioeventfds).
+/// * `legacy` - Whether the device implements the legacy (version 1) virtio-mmio layout.
+/// * `transport_features` - The policy applied to the transport features.
+/// * `features_mask` - The features hidden from the driver, and never negotiated.
+/// * `features_override` - The features offered to the driver even if the backend does not
+///   offer them. The driver can only decline those the backend does not offer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceOptions {
+    pub needs_reset_on_error: bool,
+    pub mmio_size: u64,
+    pub reconnect_retries: u64,
+    pub reconnect_backoff: u64,
+    pub reconnect_backoff_max: u64,
+    pub protocol_features: VhostUserProtocolFeatures,
+    pub irqfd: bool,
+    pub ioeventfd: bool,
+    pub legacy: bool,
+    pub transport_features: TransportPolicy,
+    pub features_mask: u64,
+    pub features_override: u64,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        Self {
+            needs_reset_on_error: false,
+            mmio_size: VIRTIO_MMIO_IO_SIZE,
+            reconnect_retries: 10,
+            reconnect_backoff: 100,
+            reconnect_backoff_max: 5000,
+            protocol_features: PROTOCOL_FEATURES.iter().fold(
+                VhostUserProtocolFeatures::empty(),
+                |features, (_, feature)| features | *feature,
+            ),
+            irqfd: true,
+            ioeventfd: true,
+            legacy: false,
+            transport_features: TransportPolicy::default(),
+            features_mask: 0,
+            features_override: 0,
+        }
+    }
+}
+
+impl DeviceOptions {
+    /// Parses a comma-separated list of `key=value` options.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The options string.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The options, or a message describing the invalid option.
+    pub fn parse(options: &str) -> Result<Self, String> {
+        let mut opts = Self::default();
+
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or(format!("malformed option '{}'", option))?;
+
+            let number =
+                || parse_u64(value).ok_or(format!("invalid number '{}' for '{}'", value, key));
+
+            match key {
+                "needs_reset_on_error" => opts.needs_reset_on_error = parse_bool(key, value)?,
+                "irqfd" => opts.irqfd = parse_bool(key, value)?,
+                "ioeventfd" => opts.ioeventfd = parse_bool(key, value)?,
+                "legacy" => opts.legacy = parse_bool(key, value)?,
+                "reconnect_retries" => opts.reconnect_retries = number()?,
+                "reconnect_backoff" => opts.reconnect_backoff = number()?,
+                "reconnect_backoff_max" => opts.reconnect_backoff_max = number()?,
+                "protocol_features" => opts.protocol_features = parse_protocol_features(value)?,
+                "features_mask" => opts.features_mask = number()?,
+                "features_override" => opts.features_override = number()?,
+                "transport_features" => opts.transport_features = parse_transport_features(value)?,
+                "mmio_size" => {
+                    opts.mmio_size = number()?;
+                    // The window must at least hold the virtio-mmio registers
+                    if opts.mmio_size < VIRTIO_MMIO_CONFIG as u64 {
+                        return Err(format!(
+                            "'{}' must be at least 0x{:x}",
+                            key, VIRTIO_MMIO_CONFIG
+                        ));
+                    }
+                }
+                _ => return Err(format!("unknown option '{}'", key)),
+            }
+        }
+
+        // The backoff grows up to its upper bound
+        if opts.reconnect_backoff > opts.reconnect_backoff_max {
+            return Err("'reconnect_backoff' must not exceed 'reconnect_backoff_max'".to_string());
+        }
+
+        // A feature is either masked or overridden
+        if opts.features_mask & opts.features_override != 0 {
+            return Err(format!(
+                "'features_mask' and 'features_override' overlap (0x{:x})",
+                opts.features_mask & opts.features_override
+            ));
+        }
+
+        // Only legacy drivers do without VIRTIO_F_VERSION_1
+        if !opts.legacy && opts.features_mask & (1 << VIRTIO_F_VERSION_1) != 0 {
+            return Err("'features_mask' must not mask VIRTIO_F_VERSION_1".to_string());
+        }
+
+        Ok(opts)
+    }
+}
+
+/// Represents the device options of every configured device, indexed by Guest ID and device address.
+#[derive(Default)]
+pub struct DeviceOptionsTable(HashMap<(u16, u64), DeviceOptions>);
+
+impl DeviceOptionsTable {
+    /// Parses the content of a device options file.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The table, or a message describing the invalid line.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut table = HashMap::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |msg: String| format!("line {}: {}", index + 1, msg);
+            let mut tokens = line.split_whitespace();
+            let (guest_id, dev_addr, options) = match (tokens.next(), tokens.next(), tokens.next())
+            {
+                (Some(guest_id), Some(dev_addr), Some(options)) if tokens.next().is_none() => {
+                    (guest_id, dev_addr, options)
+                }
+                _ => {
+                    return Err(error(
+                        "expected '<guest_id> <dev_addr> <options>'".to_string(),
+                    ))
+                }
+            };
+            let guest_id = parse_u64(guest_id)
+                .and_then(|id| u16::try_from(id).ok())
+                .ok_or(error(format!("invalid guest ID '{}'", guest_id)))?;
+            let dev_addr =
+                parse_u64(dev_addr).ok_or(error(format!("invalid address '{}'", dev_addr)))?;
+
+            table.insert(
+                (guest_id, dev_addr),
+                DeviceOptions::parse(options).map_err(error)?,
+            );
+        }
+
+        Ok(Self(table))
+    }
+
+    /// Loads the device options file pointed to by the `BAO_VHOST_DEVICE_OPTIONS` environment variable.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The table (empty if the variable is not set), or a message on failure.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var(DEVICE_OPTIONS_ENV) {
+            Ok(path) => std::fs::read_to_string(&path)
+                .map_err(|err| format!("{}: {}", path, err))
+                .and_then(|content| {
+                    Self::parse(&content).map_err(|err| format!("{}: {}", path, err))
+                }),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Returns the options of a device, or the default options if the device is not listed.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The Guest ID of the guest owning the device.
+    /// * `dev_addr` - The device address.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceOptions` - The device options.
+    pub fn get(&self, guest_id: u16, dev_addr: u64) -> DeviceOptions {
+        self.0
+            .get(&(guest_id, dev_addr))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options are parsed from a comma-separated list and unknown or malformed ones are rejected.
+    #[test]
+    fn parse_device_options() {
+        assert_eq!(DeviceOptions::parse("").unwrap(), DeviceOptions::default());
+        assert!(
+            DeviceOptions::parse("needs_reset_on_error=on")
+                .unwrap()
+                .needs_reset_on_error
+        );
+        assert!(DeviceOptions::parse("needs_reset_on_error=maybe").is_err());
+        assert!(DeviceOptions::parse("needs_reset_on_error").is_err());
+        assert!(DeviceOptions::parse("bogus=1").is_err());
+
+        let opts = DeviceOptions::parse(
+            "mmio_size=0x1000,needs_reset_on_error=off,irqfd=off,ioeventfd=0,legacy=on",
+        )
+        .unwrap();
+        assert_eq!(opts.mmio_size, 0x1000);
+        assert!(!opts.needs_reset_on_error);
+        assert!(!opts.irqfd);
+        assert!(!opts.ioeventfd);
+        assert!(opts.legacy);
+        assert!(DeviceOptions::parse("mmio_size=0x80").is_err());
+
+        let opts = DeviceOptions::parse("reconnect_retries=0,reconnect_backoff=0x10").unwrap();
+        assert_eq!(opts.reconnect_retries, 0);
+        assert_eq!(opts.reconnect_backoff, 0x10);
+        assert_eq!(opts.reconnect_backoff_max, 5000);
+        assert!(DeviceOptions::parse("reconnect_retries=-1").is_err());
+        assert!(DeviceOptions::parse("reconnect_backoff=200,reconnect_backoff_max=100").is_err());
+    }
+
+    /// The protocol features policy is a `+`-separated list of known feature names.
+    #[test]
+    fn parse_protocol_features_policy() {
+        let opts = DeviceOptions::parse("protocol_features=mq+reply_ack").unwrap();
+        assert_eq!(
+            opts.protocol_features,
+            VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
+        );
+        assert_eq!(
+            protocol_feature_names(opts.protocol_features),
+            "mq+reply_ack"
+        );
+        assert_eq!(
+            DeviceOptions::parse("protocol_features=")
+                .unwrap()
+                .protocol_features,
+            VhostUserProtocolFeatures::empty()
+        );
+        assert!(DeviceOptions::parse("protocol_features=mq+bogus").is_err());
+
+        assert_eq!(
+            protocol_feature_names(VhostUserProtocolFeatures::empty()),
+            "none"
+        );
+        assert_eq!(
+            protocol_feature_names(
+                VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::LOG_SHMFD
+            ),
+            format!("config+0x{:x}", VhostUserProtocolFeatures::LOG_SHMFD.bits())
+        );
+    }
+
+    /// Transport feature policies override the default policy, one feature at a time.
+    #[test]
+    fn parse_transport_features_policy() {
+        let opts = DeviceOptions::parse("transport_features=iommu_platform:offer+ring_reset:mask")
+            .unwrap();
+        assert_eq!(
+            opts.transport_features,
+            TransportPolicy {
+                forced: 0,
+                masked: 1 << VIRTIO_F_RING_RESET,
+            }
+        );
+        assert_eq!(
+            DeviceOptions::parse("transport_features=ring_reset:force")
+                .unwrap()
+                .transport_features
+                .forced,
+            (1 << VIRTIO_F_IOMMU_PLATFORM) | (1 << VIRTIO_F_RING_RESET)
+        );
+        assert!(DeviceOptions::parse("transport_features=iommu_platform").is_err());
+        assert!(DeviceOptions::parse("transport_features=iommu_platform:maybe").is_err());
+        assert!(DeviceOptions::parse("transport_features=bogus:mask").is_err());
+
+        assert_eq!(
+            transport_feature_names((1 << VIRTIO_F_IOMMU_PLATFORM) | 1),
+            "iommu_platform+0x1"
+        );
+        assert_eq!(transport_feature_names(0), "none");
+    }
+
+    /// The feature mask and override must not overlap, and VIRTIO_F_VERSION_1 can only be
+    /// masked on legacy devices.
+    #[test]
+    fn parse_features_mask_override() {
+        let opts =
+            DeviceOptions::parse("features_mask=0x20000000,features_override=0x10000000").unwrap();
+        assert_eq!(opts.features_mask, 1 << 29);
+        assert_eq!(opts.features_override, 1 << 28);
+
+        assert!(DeviceOptions::parse("features_mask=0x3,features_override=0x2").is_err());
+        assert!(DeviceOptions::parse("features_mask=0x100000000").is_err());
+        assert!(DeviceOptions::parse("features_mask=0x100000000,legacy=on").is_ok());
+    }
+
+    /// The options file is indexed by Guest ID and device address.
+    #[test]
+    fn parse_device_options_table() {
+        let table = DeviceOptionsTable::parse(
+            "# guest address options\n\n0 0xa003e00 needs_reset_on_error=on\n",
+        )
+        .unwrap();
+
+        assert!(table.get(0, 0xa003e00).needs_reset_on_error);
+        assert_eq!(table.get(1, 0xa003e00), DeviceOptions::default());
+        assert!(DeviceOptionsTable::parse("0 0xa003e00").is_err());
+        assert!(DeviceOptionsTable::parse("0x10000 0xa003e00 needs_reset_on_error=on").is_err());
+    }
+}
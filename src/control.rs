@@ -0,0 +1,558 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Control' module exposes a Unix domain control socket, allowing an orchestrator to
+//! manage guests and devices of a running frontend. The socket path is taken from the
+//! `BAO_VHOST_CONTROL_SOCKET` environment variable.
+//!
+//! # Protocol
+//!
+//! Each request is a single line made of a command followed by `key=value` arguments.
+//! Numbers may be given in decimal or hexadecimal (`0x` prefix). Each request gets a
+//! single line reply, either `ok [payload]` or `error <message>`.
+//!
+//! - `add_device guest=<id> id=<dev_id> irq=<irq> addr=<addr> ram_addr=<addr> ram_size=<size> shmem_path=<path> socket_path=<path> [frontend=<id>] [options=<k=v,...>]`
+//!   - The Frontend ID (`0` by default) is the one logged for the guest if it is created.
+//!   - A device whose MMIO window overlaps another device is refused with
+//!     `error OverlappingDevice { addr: <addr>, size: <size> }`, naming the window of the other device.
+//! - `remove_device guest=<id> addr=<addr>`
+//! - `list_guests` - Replies with the guest IDs.
+//! - `list_devices guest=<id>` - Replies with the device addresses of the guest.
+//! - `guest_status guest=<id>` - Replies with the Frontend ID, the lifecycle state and the number of devices of the guest.
+//! - `pause_guest guest=<id>` - Holds off the I/O requests of a running guest.
+//! - `resume_guest guest=<id>` - Resumes the I/O requests of a paused guest.
+//! - `log_level [directives=<directives>]` - Replies with the log filtering directives, after
+//!   replacing them if given (e.g. `log_level directives=info,frontend0::guest3=trace`).
+//! - `device_status guest=<id> addr=<addr>` - Replies with `key=value` pairs describing the device.
+
+use super::{
+    config::{parse_u64, protocol_feature_names, DeviceOptions},
+    frontend::BaoFrontend,
+    guest::BaoGuest,
+    logger,
+};
+use bao_sys::error::*;
+use log::debug;
+use std::collections::HashMap;
+use std::fs::{self, Permissions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+
+/// Environment variable holding the control socket path.
+pub const CONTROL_SOCKET_ENV: &str = "BAO_VHOST_CONTROL_SOCKET";
+
+/// Control socket commands.
+#[derive(Debug, PartialEq)]
+enum Command {
+    AddDevice {
+        frontend_id: u16,
+        guest_id: u16,
+        dev_id: u64,
+        dev_irq: u64,
+        dev_addr: u64,
+        ram_addr: u64,
+        ram_size: u64,
+        shmem_path: String,
+        socket_path: String,
+        options: DeviceOptions,
+    },
+    RemoveDevice {
+        guest_id: u16,
+        dev_addr: u64,
+    },
+    ListGuests,
+    ListDevices {
+        guest_id: u16,
+    },
+    GuestStatus {
+        guest_id: u16,
+    },
+    PauseGuest {
+        guest_id: u16,
+    },
+    ResumeGuest {
+        guest_id: u16,
+    },
+    LogLevel {
+        directives: Option<String>,
+    },
+    DeviceStatus {
+        guest_id: u16,
+        dev_addr: u64,
+    },
+}
+
+/// Represents the `key=value` arguments of a command.
+struct Args<'a>(HashMap<&'a str, &'a str>);
+
+impl<'a> Args<'a> {
+    /// Parses the `key=value` arguments of a command.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The argument tokens.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The arguments, or a message describing the malformed token.
+    fn parse(tokens: impl Iterator<Item = &'a str>) -> std::result::Result<Self, String> {
+        let mut args = HashMap::new();
+        for token in tokens {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or(format!("malformed argument '{}'", token))?;
+            args.insert(key, value);
+        }
+        Ok(Self(args))
+    }
+
+    /// Returns a string argument.
+    fn string(&self, key: &str) -> std::result::Result<String, String> {
+        self.0
+            .get(key)
+            .map(|value| value.to_string())
+            .ok_or(format!("missing argument '{}'", key))
+    }
+
+    /// Returns a numeric argument.
+    fn u64(&self, key: &str) -> std::result::Result<u64, String> {
+        let value = self
+            .0
+            .get(key)
+            .ok_or(format!("missing argument '{}'", key))?;
+        parse_u64(value).ok_or(format!("invalid number '{}' for '{}'", value, key))
+    }
+
+    /// Returns a Guest ID argument.
+    fn guest_id(&self) -> std::result::Result<u16, String> {
+        u16::try_from(self.u64("guest")?).map_err(|_| "invalid guest ID".to_string())
+    }
+
+    /// Returns the optional Frontend ID argument, 0 if not given.
+    fn frontend_id(&self) -> std::result::Result<u16, String> {
+        if !self.0.contains_key("frontend") {
+            return Ok(0);
+        }
+        u16::try_from(self.u64("frontend")?).map_err(|_| "invalid frontend ID".to_string())
+    }
+}
+
+impl Command {
+    /// Parses a command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The command line.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The command, or a message describing why it is invalid.
+    fn parse(line: &str) -> std::result::Result<Self, String> {
+        let mut tokens = line.split_whitespace();
+        let name = tokens.next().ok_or("empty command")?;
+        let args = Args::parse(tokens)?;
+
+        match name {
+            "add_device" => Ok(Command::AddDevice {
+                frontend_id: args.frontend_id()?,
+                guest_id: args.guest_id()?,
+                dev_id: args.u64("id")?,
+                dev_irq: args.u64("irq")?,
+                dev_addr: args.u64("addr")?,
+                ram_addr: args.u64("ram_addr")?,
+                ram_size: args.u64("ram_size")?,
+                shmem_path: args.string("shmem_path")?,
+                socket_path: args.string("socket_path")?,
+                options: match args.0.get("options") {
+                    Some(options) => DeviceOptions::parse(options)?,
+                    None => DeviceOptions::default(),
+                },
+            }),
+            "remove_device" => Ok(Command::RemoveDevice {
+                guest_id: args.guest_id()?,
+                dev_addr: args.u64("addr")?,
+            }),
+            "list_guests" => Ok(Command::ListGuests),
+            "list_devices" => Ok(Command::ListDevices {
+                guest_id: args.guest_id()?,
+            }),
+            "guest_status" => Ok(Command::GuestStatus {
+                guest_id: args.guest_id()?,
+            }),
+            "pause_guest" => Ok(Command::PauseGuest {
+                guest_id: args.guest_id()?,
+            }),
+            "resume_guest" => Ok(Command::ResumeGuest {
+                guest_id: args.guest_id()?,
+            }),
+            "log_level" => Ok(Command::LogLevel {
+                directives: args.string("directives").ok(),
+            }),
+            "device_status" => Ok(Command::DeviceStatus {
+                guest_id: args.guest_id()?,
+                dev_addr: args.u64("addr")?,
+            }),
+            _ => Err(format!("unknown command '{}'", name)),
+        }
+    }
+
+    /// Executes the command against the frontend.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend` - The frontend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, String>` - The reply payload, or a message describing the failure.
+    fn execute(self, frontend: &BaoFrontend) -> std::result::Result<String, String> {
+        match self {
+            Command::AddDevice {
+                frontend_id,
+                guest_id,
+                dev_id,
+                dev_irq,
+                dev_addr,
+                ram_addr,
+                ram_size,
+                shmem_path,
+                socket_path,
+                options,
+            } => frontend
+                .add_device(
+                    frontend_id,
+                    guest_id,
+                    dev_id,
+                    dev_irq,
+                    dev_addr,
+                    ram_addr,
+                    ram_size,
+                    shmem_path,
+                    socket_path,
+                    options,
+                )
+                .map(|_| String::new())
+                .map_err(|err| format!("{:?}", err)),
+            Command::RemoveDevice { guest_id, dev_addr } => frontend
+                .remove_device(guest_id, dev_addr)
+                .map(|_| String::new())
+                .map_err(|err| format!("{:?}", err)),
+            Command::ListGuests => Ok(frontend
+                .guests()
+                .iter()
+                .map(|guest| guest.id.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")),
+            Command::ListDevices { guest_id } => Ok(find_guest(frontend, guest_id)?
+                .devices()
+                .iter()
+                .map(|dev| format!("0x{:x}", dev.addr))
+                .collect::<Vec<_>>()
+                .join(" ")),
+            Command::GuestStatus { guest_id } => {
+                let guest = find_guest(frontend, guest_id)?;
+                Ok(format!(
+                    "frontend={} state={} devices={} errors={}",
+                    guest.frontend_id,
+                    guest.state(),
+                    guest.devices().len(),
+                    guest.errors()
+                ))
+            }
+            Command::PauseGuest { guest_id } => {
+                let guest = find_guest(frontend, guest_id)?;
+                match guest.pause() {
+                    true => Ok(String::new()),
+                    false => Err(format!("guest {} is {}", guest_id, guest.state())),
+                }
+            }
+            Command::ResumeGuest { guest_id } => {
+                let guest = find_guest(frontend, guest_id)?;
+                match guest.resume() {
+                    true => Ok(String::new()),
+                    false => Err(format!("guest {} is {}", guest_id, guest.state())),
+                }
+            }
+            Command::LogLevel { directives } => {
+                if let Some(directives) = directives {
+                    logger::set_directives(&directives)?;
+                }
+                logger::directives().ok_or("logger not installed".to_string())
+            }
+            Command::DeviceStatus { guest_id, dev_addr } => {
+                let dev = frontend
+                    .device(guest_id, dev_addr)
+                    .ok_or(format!("device 0x{:x} not found", dev_addr))?;
+                let mmio = dev.mmio.lock().unwrap();
+                Ok(format!(
+                    "guest={} id={} irq=0x{:x} addr=0x{:x} size=0x{:x} status=0x{:x} driver_features=0x{:x} protocol_features={} errors={} reconnects={}",
+                    guest_id,
+                    dev.id,
+                    dev.irq,
+                    dev.addr,
+                    dev.size,
+                    mmio.status(),
+                    mmio.driver_features(),
+                    protocol_feature_names(dev.protocol_features()),
+                    dev.errors.load(Ordering::Relaxed),
+                    dev.reconnects.load(Ordering::Relaxed)
+                ))
+            }
+        }
+    }
+}
+
+/// Finds a guest of the frontend.
+///
+/// # Arguments
+///
+/// * `frontend` - The frontend.
+/// * `guest_id` - The Guest ID.
+///
+/// # Returns
+///
+/// * `Result<Arc<BaoGuest>, String>` - The guest, or a message if there is no such guest.
+fn find_guest(frontend: &BaoFrontend, guest_id: u16) -> std::result::Result<Arc<BaoGuest>, String> {
+    frontend
+        .guest(guest_id)
+        .ok_or(format!("guest {} not found", guest_id))
+}
+
+/// Represents the control socket server.
+///
+/// # Attributes
+///
+/// * `path` - The control socket path.
+/// * `handle` - The handle of the thread accepting connections.
+/// * `stopping` - Whether the server was asked to stop accepting connections.
+pub struct ControlServer {
+    path: String,
+    handle: Option<JoinHandle<()>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    /// Creates the control socket and spawns the thread accepting connections.
+    /// A stale socket left by a previous instance is replaced, while any other file at the path
+    /// is left untouched and makes the creation fail. The socket is only accessible to the
+    /// owner of the frontend, as it allows managing every guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The control socket path.
+    /// * `frontend` - The frontend managed through the socket.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The ControlServer on success, or an Error if the socket cannot be created.
+    pub fn new(path: &str, frontend: Arc<BaoFrontend>) -> Result<Self> {
+        let map_err = |err| Error::OpenFdFailed("control socket", err);
+
+        // Remove a stale socket left by a previous instance
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path).map_err(map_err)?;
+            }
+        }
+
+        let listener = UnixListener::bind(path).map_err(map_err)?;
+        fs::set_permissions(path, Permissions::from_mode(0o600)).map_err(map_err)?;
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stop = stopping.clone();
+
+        let handle = Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    // Check if the server was woken up to stop
+                    if stop.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let fe = frontend.clone();
+                    // Serve each client in its own thread so a slow client does not block others
+                    let _ = Builder::new()
+                        .name("control client".to_string())
+                        .spawn(move || handle_client(stream, &fe));
+                }
+            })
+            .unwrap();
+
+        Ok(Self {
+            path: path.to_string(),
+            handle: Some(handle),
+            stopping,
+        })
+    }
+
+    /// Stops accepting connections and removes the control socket.
+    /// Clients already connected are served until they disconnect.
+    pub fn exit(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stopping.store(true, Ordering::Release);
+            // Wake up the thread blocked accepting connections
+            let _ = UnixStream::connect(&self.path);
+            let _ = handle.join();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Control socket path getter.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    /// Destructor function for ControlServer.
+    fn drop(&mut self) {
+        self.exit();
+    }
+}
+
+/// Serves the requests of a control socket client until it disconnects.
+///
+/// # Arguments
+///
+/// * `stream` - The client connection.
+/// * `frontend` - The frontend managed through the socket.
+fn handle_client(stream: UnixStream, frontend: &BaoFrontend) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        debug!("control request: {}", line);
+
+        let reply = match Command::parse(&line).and_then(|command| command.execute(frontend)) {
+            Ok(payload) if payload.is_empty() => "ok".to_string(),
+            Ok(payload) => format!("ok {}", payload),
+            Err(err) => format!("error {}", err),
+        };
+
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    /// Commands and their arguments are parsed, in both decimal and hexadecimal notation.
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            Command::parse("add_device guest=1 id=4 irq=0x2f addr=0xa003e00 ram_addr=0x60000000 ram_size=0x1000000 shmem_path=/dev/baoipc0 socket_path=/root/"),
+            Ok(Command::AddDevice {
+                frontend_id: 0,
+                guest_id: 1,
+                dev_id: 4,
+                dev_irq: 0x2f,
+                dev_addr: 0xa003e00,
+                ram_addr: 0x60000000,
+                ram_size: 0x1000000,
+                shmem_path: "/dev/baoipc0".to_string(),
+                socket_path: "/root/".to_string(),
+                options: DeviceOptions::default(),
+            })
+        );
+        assert_eq!(
+            Command::parse("remove_device addr=0xa003e00 guest=0"),
+            Ok(Command::RemoveDevice {
+                guest_id: 0,
+                dev_addr: 0xa003e00
+            })
+        );
+        assert_eq!(Command::parse("list_guests"), Ok(Command::ListGuests));
+        assert_eq!(
+            Command::parse("log_level directives=info,frontend0::guest3=trace"),
+            Ok(Command::LogLevel {
+                directives: Some("info,frontend0::guest3=trace".to_string())
+            })
+        );
+        assert_eq!(
+            Command::parse("guest_status guest=2"),
+            Ok(Command::GuestStatus { guest_id: 2 })
+        );
+        assert!(matches!(
+            Command::parse("add_device frontend=3 guest=1 id=4 irq=0x2f addr=0xa003e00 ram_addr=0x60000000 ram_size=0x1000000 shmem_path=/dev/baoipc0 socket_path=/root/"),
+            Ok(Command::AddDevice { frontend_id: 3, .. })
+        ));
+        assert!(Command::parse("add_device frontend=0x10000 guest=1 id=4 irq=0x2f addr=0xa003e00 ram_addr=0x60000000 ram_size=0x1000000 shmem_path=/dev/baoipc0 socket_path=/root/").is_err());
+        assert!(Command::parse("remove_device guest=0").is_err());
+        assert!(Command::parse("list_devices guest=0x10000").is_err());
+        assert!(Command::parse("device_status guest=0 addr").is_err());
+        assert!(Command::parse("reboot").is_err());
+        assert!(Command::parse("add_device guest=1 id=4 irq=0x2f addr=0xa003e00 ram_addr=0x60000000 ram_size=0x1000000 shmem_path=/dev/baoipc0 socket_path=/root/ options=bogus=1").is_err());
+    }
+
+    /// Requests sent over the socket get a single line reply.
+    #[test]
+    fn serve_requests() {
+        let dir = TempDir::new_with_prefix("/tmp/bao-vhost-frontend").unwrap();
+        let path = format!("{}/control.sock", dir.as_path().display());
+        let mut server = ControlServer::new(&path, BaoFrontend::new().unwrap()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request = |line: &str| {
+            writeln!(writer, "{}", line).unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            reply.trim_end().to_string()
+        };
+
+        assert_eq!(request("list_guests"), "ok");
+        assert!(request("remove_device guest=0 addr=0xa003e00").starts_with("error"));
+        assert!(request("list_devices guest=0").starts_with("error"));
+        assert!(request("pause_guest guest=0").starts_with("error"));
+        assert!(request("bogus").starts_with("error unknown command"));
+
+        // Stopping the server removes the socket
+        server.exit();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    /// A stale socket is replaced, while any other file at the socket path is kept.
+    #[test]
+    fn socket_path_reuse() {
+        let dir = TempDir::new_with_prefix("/tmp/bao-vhost-frontend").unwrap();
+        let path = format!("{}/control.sock", dir.as_path().display());
+
+        // A socket left behind by a previous instance
+        drop(UnixListener::bind(&path).unwrap());
+        let mut server = ControlServer::new(&path, BaoFrontend::new().unwrap()).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        server.exit();
+
+        // A regular file
+        fs::write(&path, "data").unwrap();
+        assert!(ControlServer::new(&path, BaoFrontend::new().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+    }
+}
@@ -10,14 +10,40 @@ use clap::Parser;
 use seccompiler::SeccompAction;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    fs::File,
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread::{Builder, JoinHandle},
 };
 
 use lazy_static::lazy_static;
-use vhost_user_frontend::{Generic, VhostUserConfig, VirtioDevice, VirtioDeviceType};
+use log::{debug, error, info, warn};
+use vhost::vhost_user::message::{
+    VhostUserInflight, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
+};
+use vhost::vhost_user::{Frontend, FrontendReqHandler, VhostUserFrontend};
+use vhost::{VhostBackend, VringConfigData};
+use vhost_user_frontend::{
+    Error as VhostUserFrontendError, Generic, GuestMemoryMmap, VhostUserConfig, VirtioDevice,
+    VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
+};
+use virtio_bindings::virtio_mmio::VIRTIO_MMIO_INT_CONFIG;
+use vm_memory::{GuestAddress, GuestMemory};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use super::{guest::BaoGuest, interrupt::BaoInterrupt, mmio::BaoMmio};
+use super::{
+    backendreq::{self, BaoBackendReqHandler},
+    config::{protocol_feature_names, DeviceOptions},
+    error::{DeviceError, DeviceResult},
+    guest::BaoGuest,
+    interrupt::BaoInterrupt,
+    logger::device_target,
+    mmio::BaoMmio,
+};
 use bao_sys::{defines::*, error::*, types::*};
 
 #[derive(Parser, Debug)]
@@ -99,6 +125,33 @@ lazy_static! {
     };
 }
 
+/// Waits for events on a set of file descriptors.
+///
+/// # Arguments
+///
+/// * `fds` - The file descriptors and the events to wait for.
+/// * `timeout` - The timeout in milliseconds, or None to wait forever.
+///
+/// # Return
+///
+/// * `Vec<i16>` - The events returned for each file descriptor (all empty on timeout or interruption).
+pub fn poll(fds: &[(RawFd, i16)], timeout: Option<u64>) -> Vec<i16> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&(fd, events)| libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.map_or(-1, |ms| ms.min(i32::MAX as u64) as i32);
+
+    // SAFETY: The pollfd array is valid for the duration of the call.
+    unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+
+    pollfds.iter().map(|pollfd| pollfd.revents).collect()
+}
+
 /// Bao Device.
 ///
 /// # Attributes
@@ -108,16 +161,42 @@ lazy_static! {
 /// * `id` - The id of the device.
 /// * `irq` - The irq of the device.
 /// * `addr` - The address of the device.
+/// * `size` - The size of the device MMIO window.
+/// * `options` - The options of the device.
 /// * `guest` - The guest that owns the device.
+/// * `errors` - The number of faulty accesses to the device.
+/// * `target` - The log target of the device.
+/// * `reconnects` - The number of successful reconnections to the backend.
+/// * `config_generation` - The configuration generation.
+/// * `name` - The name of the device type.
+/// * `socket` - The vhost-user socket of the backend.
+/// * `protocol_features` - The vhost-user protocol features negotiated with the backend.
+/// * `inflight` - The inflight region shared with the backend, kept across backend restarts.
 /// * `interrupt` - The interrupt of the device.
+/// * `monitor` - The kill eventfd and the thread watching the backend connection.
+/// * `backend_req` - The socket and the thread serving the backend request channel.
+/// * `this` - A weak reference to the device itself, handed to the backend request handler.
 pub struct BaoDevice {
     pub gdev: Mutex<Generic>,
     pub mmio: Mutex<BaoMmio>,
     pub id: u64,
     pub irq: u64,
     pub addr: u64,
+    pub size: u64,
+    pub options: DeviceOptions,
     pub guest: Arc<BaoGuest>,
+    pub errors: AtomicU64,
+    pub target: String,
+    pub reconnects: AtomicU64,
+    config_generation: AtomicU32,
+    name: &'static str,
+    socket: String,
+    protocol_features: Mutex<VhostUserProtocolFeatures>,
+    inflight: Mutex<Option<(VhostUserInflight, File)>>,
     interrupt: Mutex<Option<Arc<BaoInterrupt>>>,
+    monitor: Mutex<Option<(EventFd, JoinHandle<()>)>>,
+    backend_req: Mutex<Option<(OwnedFd, JoinHandle<()>)>>,
+    this: Weak<Self>,
 }
 
 impl BaoDevice {
@@ -131,11 +210,13 @@ impl BaoDevice {
     /// * `ram_addr` - The address of the guest RAM.
     /// * `ram_size` - The size of the guest RAM.
     /// * `socket_path` - The path to the vhost-user socket.
+    /// * `options` - The options of the device.
     /// * `guest` - The guest that owns the device.
     ///
     /// # Return
     ///
-    /// * `Result<Arc<Self>>` - A Result object containing the BaoDevice.
+    /// * `DeviceResult<Arc<Self>>` - A Result object containing the BaoDevice, or Exiting if the
+    ///   addition was cancelled while waiting for the backend.
     pub fn new(
         id: u64,
         irq: u64,
@@ -144,8 +225,9 @@ impl BaoDevice {
         ram_size: u64,
         shmem_path: String,
         socket_path: String,
+        options: DeviceOptions,
         guest: Arc<BaoGuest>,
-    ) -> Result<Arc<Self>> {
+    ) -> DeviceResult<Arc<Self>> {
         // Extract the supported devices HashMap
         let mut devices = DEVICES.lock().unwrap();
 
@@ -157,50 +239,63 @@ impl BaoDevice {
             .get_mut(&compatible)
             .ok_or(Error::BaoDevNotSupported(compatible))?;
 
-        // Extract the device type
-        let device_type = VirtioDeviceType::from(dev.name);
+        // Generate the vhost-user socket path
+        let name = dev.name;
+        let socket = socket_path + dev.name + ".sock" + &dev.index();
+        drop(devices);
 
-        // Extract the number of queues and queue size
-        let (num, size) = device_type.queue_num_and_size();
+        // Log target of the device
+        let target = device_target(guest.frontend_id, guest.id, addr);
 
-        // Create the vhost-user configuration
-        let vu_cfg = VhostUserConfig {
-            socket: socket_path + dev.name + ".sock" + &dev.index(),
-            num_queues: num,
-            queue_size: size as u16,
-        };
-
-        println!(
-            "Connecting to {} device backend over {} socket..",
-            dev.name, vu_cfg.socket
+        info!(
+            target: &target,
+            "connecting to backend device={} socket={}", name, socket
         );
 
-        // Create the Generic vhost-user device
-        let gdev = Generic::new(
-            vu_cfg,
-            SeccompAction::Allow,
-            EventFd::new(EFD_NONBLOCK).unwrap(),
-            device_type,
-        )
-        .map_err(Error::VhostFrontendError)?;
+        // Wait for the backend, then create the Generic vhost-user device
+        Self::wait_backend(&socket, &options, &guest.cancel)?;
+        let gdev = Self::connect(name, &socket)?;
 
-        println!("Connected to {} device backend.", dev.name);
+        info!(target: &target, "connected to backend device={}", name);
 
         // Create the BaoMmio device
-        let mmio = match BaoMmio::new(&gdev, guest.clone(), addr, ram_addr, ram_size, shmem_path) {
+        let mmio = match BaoMmio::new(
+            &gdev,
+            guest.clone(),
+            addr,
+            ram_addr,
+            ram_size,
+            shmem_path,
+            target.clone(),
+            options.ioeventfd,
+            options.legacy,
+        ) {
             Ok(mmio) => mmio,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         };
 
         // Create the BaoDevice
-        let dev = Arc::new(Self {
+        let dev = Arc::new_cyclic(|this| Self {
             gdev: Mutex::new(gdev),
             mmio: Mutex::new(mmio),
             id,
             irq,
             addr,
+            size: options.mmio_size,
+            options,
             guest,
+            errors: AtomicU64::new(0),
+            target,
+            reconnects: AtomicU64::new(0),
+            config_generation: AtomicU32::new(0),
+            name,
+            socket,
+            protocol_features: Mutex::new(VhostUserProtocolFeatures::empty()),
+            inflight: Mutex::new(None),
             interrupt: Mutex::new(None),
+            monitor: Mutex::new(None),
+            backend_req: Mutex::new(None),
+            this: this.clone(),
         });
 
         // Create the BaoInterrupt
@@ -209,13 +304,498 @@ impl BaoDevice {
                 // Store the interrupt
                 *dev.interrupt.lock().unwrap() = Some(int);
             }
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         }
 
+        // Watch the backend connection
+        dev.clone().start_monitor();
+
         // Return the BaoDevice
         Ok(dev)
     }
 
+    /// Waits for the backend to listen on its socket, following the reconnection policy of the
+    /// device, so that a device addition does not wait forever for a backend that is not
+    /// started and can be cancelled when the frontend exits. The connection is attempted
+    /// anyway once the attempts are exhausted, failing if the backend is still missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The vhost-user socket of the backend.
+    /// * `options` - The options of the device.
+    /// * `cancel` - The eventfd signaled when the device additions are cancelled.
+    ///
+    /// # Return
+    ///
+    /// * `DeviceResult<()>` - Ok(()) once the connection can be attempted, or Exiting if the
+    ///   addition was cancelled.
+    fn wait_backend(socket: &str, options: &DeviceOptions, cancel: &EventFd) -> DeviceResult<()> {
+        let mut backoff = options.reconnect_backoff;
+
+        for _ in 0..options.reconnect_retries {
+            if Path::new(socket).exists() {
+                return Ok(());
+            }
+
+            // Wait before the next attempt, bailing out if the addition is cancelled meanwhile
+            if poll(&[(cancel.as_raw_fd(), libc::POLLIN)], Some(backoff))[0] != 0 {
+                return Err(DeviceError::Exiting);
+            }
+            backoff = backoff.saturating_mul(2).min(options.reconnect_backoff_max);
+        }
+
+        Ok(())
+    }
+
+    /// Connects a Generic vhost-user device to a backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the device type.
+    /// * `socket` - The vhost-user socket of the backend.
+    ///
+    /// # Return
+    ///
+    /// * `Result<Generic>` - A Result containing the connected Generic vhost-user device.
+    fn connect(name: &'static str, socket: &str) -> Result<Generic> {
+        // Extract the device type
+        let device_type = VirtioDeviceType::from(name);
+
+        // Extract the number of queues and queue size
+        let (num, size) = device_type.queue_num_and_size();
+
+        // Create the vhost-user configuration
+        let vu_cfg = VhostUserConfig {
+            socket: socket.to_string(),
+            num_queues: num,
+            queue_size: size as u16,
+        };
+
+        // Create the Generic vhost-user device
+        Generic::new(
+            vu_cfg,
+            SeccompAction::Allow,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            device_type,
+        )
+        .map_err(Error::VhostFrontendError)
+    }
+
+    /// Negotiates the driver features and the vhost-user protocol features with the backend.
+    /// The protocol features are the ones offered by the backend and allowed by the
+    /// `protocol_features` policy of the device, none if the backend does not offer
+    /// VHOST_USER_F_PROTOCOL_FEATURES. The ones recorded are the ones the Generic device
+    /// acknowledged to the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdev` - The Generic vhost-user device connected to the backend.
+    /// * `features` - The features acknowledged by the driver.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    pub fn negotiate_features(&self, gdev: &mut Generic, features: u64) -> Result<()> {
+        // The protocol features are negotiated on every connection, so a restarted backend
+        // may offer a different set, or none at all
+        let backend_features = gdev.socket_handle().get_features().map_err(|err| {
+            Error::VhostFrontendError(VhostUserFrontendError::VhostUserGetFeatures(err))
+        })?;
+        let offered = if backend_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            gdev.socket_handle()
+                .get_protocol_features()
+                .map_err(|err| {
+                    Error::VhostFrontendError(VhostUserFrontendError::VhostUserGetProtocolFeatures(
+                        err,
+                    ))
+                })?
+        } else {
+            VhostUserProtocolFeatures::empty()
+        };
+        let allowed = offered & self.options.protocol_features;
+
+        let (_, acked) = gdev
+            .negotiate_features(features, allowed)
+            .map_err(Error::VhostFrontendError)?;
+        let acked = VhostUserProtocolFeatures::from_bits_truncate(acked);
+        if acked != allowed {
+            warn!(
+                target: &self.target,
+                "protocol features acked={} differ from the allowed ones {}",
+                protocol_feature_names(acked),
+                protocol_feature_names(allowed)
+            );
+        }
+        *self.protocol_features.lock().unwrap() = acked;
+        self.setup_backend_req(gdev, acked)?;
+
+        info!(
+            target: &self.target,
+            "negotiated protocol features offered={} policy={} acked={}",
+            protocol_feature_names(offered),
+            protocol_feature_names(self.options.protocol_features),
+            protocol_feature_names(acked)
+        );
+
+        Ok(())
+    }
+
+    /// Sets up the backend request channel if VHOST_USER_PROTOCOL_F_BACKEND_REQ was negotiated,
+    /// replacing the channel of a previous backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdev` - The Generic vhost-user device connected to the backend.
+    /// * `acked` - The negotiated protocol features.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn setup_backend_req(
+        &self,
+        gdev: &mut Generic,
+        acked: VhostUserProtocolFeatures,
+    ) -> Result<()> {
+        self.stop_backend_req();
+
+        if !acked.contains(VhostUserProtocolFeatures::BACKEND_REQ) {
+            return Ok(());
+        }
+
+        let map_err = |err| {
+            Error::VhostFrontendError(VhostUserFrontendError::VhostUserSetBackendRequestFd(err))
+        };
+
+        // Create the channel and hand its other end to the backend
+        let handler = Arc::new(BaoBackendReqHandler::new(self.this.clone()));
+        let mut channel = FrontendReqHandler::new(handler).map_err(map_err)?;
+        channel.set_reply_ack_flag(acked.contains(VhostUserProtocolFeatures::REPLY_ACK));
+        gdev.socket_handle()
+            .set_backend_request_fd(&channel.get_tx_raw_fd())
+            .map_err(map_err)?;
+
+        // Keep a duplicate of the socket to stop the thread
+        // SAFETY: The socket is owned by the channel, which is alive.
+        let socket = unsafe { BorrowedFd::borrow_raw(channel.as_raw_fd()) }
+            .try_clone_to_owned()
+            .map_err(|err| Error::OpenFdFailed("backend request channel", err))?;
+
+        let target = self.target.clone();
+        let handle = Builder::new()
+            .name(format!("backend req {:x}", self.addr))
+            .spawn(move || backendreq::serve(channel, target))
+            .unwrap();
+
+        *self.backend_req.lock().unwrap() = Some((socket, handle));
+        debug!(target: &self.target, "backend request channel set up");
+
+        Ok(())
+    }
+
+    /// Stops serving the backend request channel, if any, waiting for its thread.
+    fn stop_backend_req(&self) {
+        if let Some((socket, handle)) = self.backend_req.lock().unwrap().take() {
+            // Shutting the socket down wakes the thread up
+            // SAFETY: The socket is a valid file descriptor owned by us.
+            unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_RDWR) };
+            let _ = handle.join();
+        }
+    }
+
+    /// Protocol features getter.
+    ///
+    /// # Return
+    ///
+    /// * `VhostUserProtocolFeatures` - The vhost-user protocol features negotiated with the backend.
+    pub fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        *self.protocol_features.lock().unwrap()
+    }
+
+    /// Hands the inflight region to the backend, allocating it through the backend on first use.
+    /// The region outlives the backend, so a restarted backend gets the same one back and can
+    /// resubmit the requests that were in flight. Nothing is done if the backend does not
+    /// support VHOST_USER_PROTOCOL_F_INFLIGHT_SHMEM.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdev` - The Generic vhost-user device connected to the backend.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    pub fn set_inflight(&self, gdev: &mut Generic) -> Result<()> {
+        if !self
+            .protocol_features()
+            .contains(VhostUserProtocolFeatures::INFLIGHT_SHMEM)
+        {
+            return Ok(());
+        }
+
+        let mut inflight = self.inflight.lock().unwrap();
+        let vu = gdev.socket_handle();
+
+        // Allocate the region, sized by the backend for the queues of the device
+        if inflight.is_none() {
+            let (num, size) = VirtioDeviceType::from(self.name).queue_num_and_size();
+            let request = VhostUserInflight {
+                mmap_size: 0,
+                mmap_offset: 0,
+                num_queues: num as u16,
+                queue_size: size as u16,
+            };
+            *inflight = Some(vu.get_inflight_fd(&request).map_err(|err| {
+                Error::VhostFrontendError(VhostUserFrontendError::VhostUserGetInflight(err))
+            })?);
+            info!(target: &self.target, "allocated inflight region");
+        }
+
+        let (region, file) = inflight.as_ref().unwrap();
+        vu.set_inflight_fd(region, file.as_raw_fd()).map_err(|err| {
+            Error::VhostFrontendError(VhostUserFrontendError::VhostUserSetInflight(err))
+        })
+    }
+
+    /// Resets the backend side of the device once its vrings are stopped: the requests in
+    /// flight are dropped with the inflight region, and the backend is asked to reset its
+    /// state if VHOST_USER_PROTOCOL_F_RESET_DEVICE was negotiated.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdev` - The Generic vhost-user device connected to the backend.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    pub fn reset_backend(&self, gdev: &mut Generic) -> Result<()> {
+        // A new inflight region is allocated on the next activation
+        *self.inflight.lock().unwrap() = None;
+
+        if !self
+            .protocol_features()
+            .contains(VhostUserProtocolFeatures::RESET_DEVICE)
+        {
+            return Ok(());
+        }
+
+        gdev.socket_handle().reset_device().map_err(|err| {
+            Error::VhostFrontendError(VhostUserFrontendError::VhostUserResetDevice(err))
+        })
+    }
+
+    /// Runs a function against the vhost-user connection of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The function, called with the vhost-user frontend endpoint.
+    ///
+    /// # Return
+    ///
+    /// * `T` - The value returned by the function.
+    pub fn with_vhost_user<T>(&self, f: impl FnOnce(&mut Frontend) -> T) -> T {
+        f(self.gdev.lock().unwrap().socket_handle())
+    }
+
+    /// Enables a virtqueue the driver made ready after the device was activated, setting its
+    /// vring up on the backend as the activation does.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The guest memory.
+    /// * `index` - Index of the virtqueue.
+    /// * `vring` - The vring laid out by the driver, with guest addresses.
+    /// * `base` - The vring base: the next available index for a split virtqueue, or the
+    ///   available index and wrap counter (bits 0-15) and the used index and wrap counter
+    ///   (bits 16-31) for a packed one.
+    /// * `kick` - The kick eventfd of the virtqueue.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    pub fn enable_vring(
+        &self,
+        mem: &GuestMemoryMmap,
+        index: usize,
+        vring: &VringConfigData,
+        base: u32,
+        kick: &EventFd,
+    ) -> Result<()> {
+        // The backend maps the rings through the memory table, so hand it frontend addresses
+        let host_addr = |addr: u64| {
+            mem.get_host_address(GuestAddress(addr))
+                .map(|addr| addr as u64)
+                .map_err(|_| Error::MmapGuestMemoryFailed)
+        };
+        let config = VringConfigData {
+            desc_table_addr: host_addr(vring.desc_table_addr)?,
+            used_ring_addr: host_addr(vring.used_ring_addr)?,
+            avail_ring_addr: host_addr(vring.avail_ring_addr)?,
+            ..vring.clone()
+        };
+        // The vhost frontend sends a 16-bit base, which carries the available index and wrap
+        // counter of a packed vring only: the backend starts its used state from them
+        if base >> 16 != 0 && base >> 16 != base & 0xffff {
+            warn!(
+                target: &self.target,
+                "queue {} used state 0x{:x} cannot be handed to the backend",
+                index,
+                base >> 16
+            );
+        }
+        let call = self
+            .interrupt()
+            .notifier(VirtioInterruptType::Queue(index as u16))
+            .unwrap();
+
+        self.with_vhost_user(|vu| {
+            vu.set_vring_num(index, config.queue_size)
+                .map_err(VhostUserFrontendError::VhostUserSetVringNum)?;
+            vu.set_vring_addr(index, &config)
+                .map_err(VhostUserFrontendError::VhostUserSetVringAddr)?;
+            vu.set_vring_base(index, base as u16)
+                .map_err(VhostUserFrontendError::VhostUserSetVringBase)?;
+            vu.set_vring_call(index, &call)
+                .map_err(VhostUserFrontendError::VhostUserSetVringCall)?;
+            vu.set_vring_kick(index, kick)
+                .map_err(VhostUserFrontendError::VhostUserSetVringKick)?;
+            vu.set_vring_enable(index, true)
+                .map_err(VhostUserFrontendError::VhostUserSetVringEnable)
+        })
+        .map_err(Error::VhostFrontendError)?;
+
+        info!(target: &self.target, "enabled queue {}", index);
+        Ok(())
+    }
+
+    /// Disables a virtqueue of the activated device, stopping its vring on the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the virtqueue.
+    ///
+    /// # Return
+    ///
+    /// * `Result<u32>` - A Result containing the vring base the backend stopped at, or an
+    ///   Error on failure.
+    pub fn disable_vring(&self, index: usize) -> Result<u32> {
+        let base = self
+            .with_vhost_user(|vu| {
+                vu.set_vring_enable(index, false)
+                    .map_err(VhostUserFrontendError::VhostUserSetVringEnable)?;
+                vu.get_vring_base(index)
+                    .map_err(VhostUserFrontendError::VhostUserGetVringBase)
+            })
+            .map_err(Error::VhostFrontendError)?;
+
+        info!(target: &self.target, "disabled queue {} base=0x{:x}", index, base);
+        Ok(base)
+    }
+
+    /// Spawns the thread that watches the backend connection and reconnects when the
+    /// backend hangs up.
+    fn start_monitor(self: Arc<Self>) {
+        let kill = EventFd::new(EFD_NONBLOCK).unwrap();
+        let monitor_kill = kill.try_clone().unwrap();
+        let dev = self.clone();
+
+        let handle = Builder::new()
+            .name(format!("monitor {:x}", self.addr))
+            .spawn(move || dev.watch_backend(monitor_kill))
+            .unwrap();
+
+        *self.monitor.lock().unwrap() = Some((kill, handle));
+    }
+
+    /// Watches the backend connection until the device exits or the backend cannot be reached anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `kill` - The eventfd signaled when the device exits.
+    fn watch_backend(&self, kill: EventFd) {
+        loop {
+            // The socket changes on every reconnection
+            let fd = self.with_vhost_user(|vu| vu.as_raw_fd());
+
+            let revents = poll(
+                &[(fd, libc::POLLRDHUP), (kill.as_raw_fd(), libc::POLLIN)],
+                None,
+            );
+            if revents[1] != 0 {
+                return;
+            }
+            if revents[0] == 0 {
+                // Interrupted
+                continue;
+            }
+
+            warn!(target: &self.target, "backend hung up socket={}", self.socket);
+            if !self.reconnect(&kill) {
+                return;
+            }
+        }
+    }
+
+    /// Reconnects to a restarted backend and replays the device state, following the
+    /// reconnection policy of the device. The device moves to DEVICE_NEEDS_RESET if the
+    /// backend cannot be reached within the allowed attempts.
+    ///
+    /// # Arguments
+    ///
+    /// * `kill` - The eventfd signaled when the device exits.
+    ///
+    /// # Return
+    ///
+    /// * `bool` - Whether the device is connected to the backend again.
+    fn reconnect(&self, kill: &EventFd) -> bool {
+        let mut backoff = self.options.reconnect_backoff;
+
+        // Stop serving the requests of the previous backend before any attempt, as waiting for
+        // its thread while the replay holds off the I/O accesses could deadlock
+        self.stop_backend_req();
+
+        for attempt in 1..=self.options.reconnect_retries {
+            // Wait before the attempt, bailing out if the device exits meanwhile
+            if poll(&[(kill.as_raw_fd(), libc::POLLIN)], Some(backoff))[0] != 0 {
+                return false;
+            }
+            backoff = backoff
+                .saturating_mul(2)
+                .min(self.options.reconnect_backoff_max);
+
+            // Connect without holding any lock, then replay the state with the I/O accesses
+            // to the device held off
+            let ret = Self::connect(self.name, &self.socket).and_then(|mut gdev| {
+                let mut mmio = self.mmio.lock().unwrap();
+                mmio.replay(&mut gdev, self)?;
+                *self.gdev.lock().unwrap() = gdev;
+                Ok(())
+            });
+
+            match ret {
+                Ok(()) => {
+                    self.reconnects.fetch_add(1, Ordering::Relaxed);
+                    info!(
+                        target: &self.target,
+                        "reconnected to backend socket={} attempt={}", self.socket, attempt
+                    );
+                    return true;
+                }
+                Err(err) => warn!(
+                    target: &self.target,
+                    "reconnection failed socket={} attempt={}: {:?}", self.socket, attempt, err
+                ),
+            }
+        }
+
+        error!(
+            target: &self.target,
+            "giving up on backend socket={} retries={}",
+            self.socket,
+            self.options.reconnect_retries
+        );
+        self.set_needs_reset();
+        false
+    }
+
     /// Interrupt getter.
     ///
     /// # Return
@@ -240,14 +820,78 @@ impl BaoDevice {
         self.mmio.lock().unwrap().io_event(req, self)
     }
 
+    /// Moves the device to DEVICE_NEEDS_RESET and notifies the driver with a configuration change interrupt.
+    pub fn set_needs_reset(&self) {
+        warn!(target: &self.target, "device needs reset id={}", self.id);
+
+        self.mmio.lock().unwrap().set_needs_reset();
+        self.signal_config();
+    }
+
+    /// Notifies the driver that the configuration space changed, with a new configuration
+    /// generation and a configuration change interrupt.
+    /// It takes no lock held while handling an access, so it can be called while the device
+    /// is waiting for the backend.
+    pub fn notify_config_change(&self) {
+        self.bump_config_generation();
+        self.signal_config();
+    }
+
+    /// Raises a configuration change interrupt.
+    pub fn signal_config(&self) {
+        if let Some(interrupt) = self.interrupt.lock().unwrap().as_ref() {
+            let _ = interrupt.signal(VIRTIO_MMIO_INT_CONFIG);
+        }
+    }
+
+    /// Starts a new configuration generation, as the configuration space may have changed.
+    pub fn bump_config_generation(&self) {
+        self.config_generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Configuration generation getter.
+    ///
+    /// # Return
+    ///
+    /// * `u32` - The configuration generation reported through the ConfigGeneration register.
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation.load(Ordering::Acquire)
+    }
+
     /// Method to exit/deactivate the BaoDevice.
-    pub fn exit(&self) {
+    /// Every step runs even if a previous one failed.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or the first Error raised on failure.
+    pub fn exit(&self) -> Result<()> {
+        let mut ret = Ok(());
+
+        // Stop watching the backend connection, as the device is about to shut it down
+        if let Some((kill, handle)) = self.monitor.lock().unwrap().take() {
+            let _ = kill.write(1);
+            let _ = handle.join();
+        }
+        // Stop serving the backend requests
+        self.stop_backend_req();
+
+        // Deassign the irqfd and drop the interrupt, which holds a reference to the device
         if let Some(interrupt) = self.interrupt.lock().unwrap().take() {
-            interrupt.exit().unwrap();
+            if let Err(err) = interrupt.exit() {
+                error!(target: &self.target, "irqfd deassignment failed: {:?}", err);
+                ret = ret.and(Err(err));
+            }
         }
         // Deactivate the device
         self.gdev.lock().unwrap().reset();
         // Shutdown the device
         self.gdev.lock().unwrap().shutdown();
+        // Deassign the ioeventfds
+        if let Err(err) = self.mmio.lock().unwrap().exit() {
+            error!(target: &self.target, "ioeventfds deassignment failed: {:?}", err);
+            ret = ret.and(Err(err));
+        }
+
+        ret
     }
 }
@@ -5,6 +5,11 @@
 
 //! The 'Device Model' module contains the Bao Device Model, which is responsible for interacting with the
 //! I/O Request Management System inside the kernel via IOCTLs to Bao the device file descriptor `/dev/bao`.
+//!
+//! The operations of the I/O Request Management System are described by the `DeviceModel` trait, so the
+//! rest of the frontend does not depend on a Bao-enabled kernel. The `BaoDeviceModel` implements it on
+//! top of `/dev/bao`, while the `BaoSimDeviceModel` (see the 'Simulator' module, only built for tests)
+//! implements it in memory.
 
 #![allow(dead_code)]
 
@@ -13,6 +18,88 @@ use libc::ioctl;
 use std::fs::{File, OpenOptions};
 use std::os::unix::io::AsRawFd;
 
+/// Operations of the I/O Request Management System used by the frontend.
+///
+/// All methods take a shared reference, since the guest I/O thread blocks inside
+/// `attach_io_client`/`request_io` while other threads register ioeventfds or irqfds.
+pub trait DeviceModel: Send + Sync {
+    /// Destroys the VirtIO backend of the guest.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn destroy(&self) -> Result<()>;
+
+    /// Creates a new I/O client.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn create_io_client(&self) -> Result<()>;
+
+    /// Destroys the I/O client.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn destroy_io_client(&self) -> Result<()>;
+
+    /// Attaches the I/O client, waiting until an I/O request is available.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn attach_io_client(&self) -> Result<()>;
+
+    /// Requests an I/O request.
+    ///
+    /// # Return
+    ///
+    /// * `Result<BaoIoRequest>` - A Result containing the BaoIoRequest object on success.
+    fn request_io(&self) -> Result<BaoIoRequest>;
+
+    /// Notifies I/O request completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The BaoIoRequest to be notified.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()>;
+
+    /// Notifies the guest about a Used Buffer Notification or
+    /// a Configuration Change Notification.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn notify_guest(&self) -> Result<()>;
+
+    /// Assigns or deassigns an ioeventfd, depending on the `BAO_IOEVENTFD_FLAG_DEASSIGN` flag.
+    ///
+    /// # Arguments
+    ///
+    /// * `ev` - The BaoIoEventFd to be created.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn create_ioeventfd(&self, ev: BaoIoEventFd) -> Result<()>;
+
+    /// Assigns or deassigns an irqfd, depending on the `BAO_IRQFD_FLAG_DEASSIGN` flag.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - The BaoIrqFd to be created.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn create_irqfd(&self, irq: BaoIrqFd) -> Result<()>;
+}
+
 /// Represents a BaoDeviceModel.
 ///
 /// # Attributes
@@ -87,13 +174,15 @@ impl BaoDeviceModel {
             }
         }
     }
+}
 
+impl DeviceModel for BaoDeviceModel {
     /// Destroys the BaoDeviceModel.
     ///
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn destroy(&mut self) -> Result<()> {
+    fn destroy(&self) -> Result<()> {
         // Destroy the VM VirtIO backend
         unsafe {
             let ret = ioctl(
@@ -109,12 +198,6 @@ impl BaoDeviceModel {
                 ));
             }
         }
-        // Close the file
-        self.fd = -1;
-        self.guest_fd = -1;
-        self.ram_addr = 0;
-        self.ram_size = 0;
-
         // Return Ok(()) on success
         Ok(())
     }
@@ -124,7 +207,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn create_io_client(&self) -> Result<()> {
+    fn create_io_client(&self) -> Result<()> {
         // Create a new I/O client
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IO_CREATE_CLIENT(), &self.guest_fd);
@@ -146,7 +229,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn destroy_io_client(&self) -> Result<()> {
+    fn destroy_io_client(&self) -> Result<()> {
         // Destroy the I/O client
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IO_DESTROY_CLIENT());
@@ -168,7 +251,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn attach_io_client(&self) -> Result<()> {
+    fn attach_io_client(&self) -> Result<()> {
         // Attach the I/O client
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IO_ATTACH_CLIENT());
@@ -190,7 +273,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<BaoIoRequest>` - A Result containing the BaoIoRequest object on success.
-    pub fn request_io(&self) -> Result<BaoIoRequest> {
+    fn request_io(&self) -> Result<BaoIoRequest> {
         // Create a new I/O request
         let mut request = BaoIoRequest {
             virtio_id: 0,
@@ -228,7 +311,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
         // Notify I/O request completion
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED(), &req);
@@ -251,7 +334,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn notify_guest(&self) -> Result<()> {
+    fn notify_guest(&self) -> Result<()> {
         // Notify the guest
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IO_NOTIFY_GUEST());
@@ -277,7 +360,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn create_ioeventfd(&self, ev: BaoIoEventFd) -> Result<()> {
+    fn create_ioeventfd(&self, ev: BaoIoEventFd) -> Result<()> {
         // Create a new I/O event file descriptor
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IOEVENTFD(), &ev);
@@ -303,7 +386,7 @@ impl BaoDeviceModel {
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn create_irqfd(&self, irq: BaoIrqFd) -> Result<()> {
+    fn create_irqfd(&self, irq: BaoIrqFd) -> Result<()> {
         // Create a new IRQ file descriptor
         unsafe {
             let ret = ioctl(self.guest_fd, BAO_IOCTL_IRQFD(), &irq);
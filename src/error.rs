@@ -0,0 +1,49 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Error' module contains the errors raised by the frontend itself when adding a device,
+//! which callers (e.g. the control socket) need to tell apart from the `bao_sys` errors raised
+//! by the layers below.
+
+use bao_sys::error::Error;
+use std::fmt;
+
+/// Represents an error raised while adding a device.
+///
+/// # Variants
+///
+/// * `OverlappingDevice` - The MMIO window of the device overlaps the window of another device,
+///   whose base address and size are given.
+/// * `Exiting` - The frontend is exiting, so the device was not added.
+/// * `Bao` - An error raised by the layers below.
+pub enum DeviceError {
+    OverlappingDevice { addr: u64, size: u64 },
+    Exiting,
+    Bao(Error),
+}
+
+impl fmt::Debug for DeviceError {
+    /// Formats the error, the errors raised by the layers below being formatted as they are.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::OverlappingDevice { addr, size } => write!(
+                f,
+                "OverlappingDevice {{ addr: 0x{:x}, size: 0x{:x} }}",
+                addr, size
+            ),
+            DeviceError::Exiting => write!(f, "Exiting"),
+            DeviceError::Bao(err) => fmt::Debug::fmt(err, f),
+        }
+    }
+}
+
+impl From<Error> for DeviceError {
+    fn from(err: Error) -> Self {
+        DeviceError::Bao(err)
+    }
+}
+
+/// Result type of the device additions.
+pub type DeviceResult<T> = std::result::Result<T, DeviceError>;
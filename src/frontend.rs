@@ -43,16 +43,34 @@
 //!     └── Device 2.2.2
 //!
 
-use super::{device::BaoDevice, guest::BaoGuest};
+use super::{
+    config::DeviceOptions,
+    device::BaoDevice,
+    error::{DeviceError, DeviceResult},
+    guest::BaoGuest,
+    logger::guest_target,
+};
 use bao_sys::error::*;
+use log::error;
 use std::{
-    sync::{Arc, Mutex},
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
     thread::JoinHandle,
 };
 
 /// Represents a collection of BaoGuests.
+///
+/// # Attributes
+///
+/// * `guests` - The guests of the frontend.
+/// * `adding` - The number of device additions in flight per Guest ID.
+/// * `exiting` - Whether the frontend is exiting, refusing new devices.
 #[derive(Default)]
-struct FrontendGuests(Vec<Arc<BaoGuest>>);
+struct FrontendGuests {
+    guests: Vec<Arc<BaoGuest>>,
+    adding: HashMap<u16, usize>,
+    exiting: bool,
+}
 
 impl FrontendGuests {
     /// Finds a guest with the given Guest ID.
@@ -67,13 +85,17 @@ impl FrontendGuests {
     fn find(&self, guest_id: u16) -> Option<Arc<BaoGuest>> {
         // Searches for a guest with the provided Guest ID in the internal vector.
         // Returns a cloned Arc to the found guest or None if not found.
-        self.0.iter().find(|guest| guest.id == guest_id).cloned()
+        self.guests
+            .iter()
+            .find(|guest| guest.id == guest_id)
+            .cloned()
     }
 
     /// Adds a new guest with the provided Guest ID to the collection.
     ///
     /// # Arguments
     ///
+    /// * `frontend_id` - The Frontend ID of the frontend the guest to be added belongs to.
     /// * `guest_id` - The Guest ID of the guest to be added.
     /// * `ram_addr` - The RAM base address of the guest to be added.
     /// * `ram_size` - The RAM size of the guest to be added.
@@ -81,12 +103,18 @@ impl FrontendGuests {
     /// # Returns
     ///
     /// * `Result<Arc<BaoGuest>>` - A cloned Arc to the newly created guest as a Result.
-    fn add(&mut self, guest_id: u16, ram_addr: u64, ram_size: u64) -> Result<Arc<BaoGuest>> {
+    fn add(
+        &mut self,
+        frontend_id: u16,
+        guest_id: u16,
+        ram_addr: u64,
+        ram_size: u64,
+    ) -> Result<Arc<BaoGuest>> {
         // Creates a new BaoGuest with the given Guest ID.
-        let guest = BaoGuest::new(guest_id, ram_addr, ram_size)?;
+        let guest = BaoGuest::new(frontend_id, guest_id, ram_addr, ram_size)?;
 
         // Clones the Arc of the new guest and appends it to the internal vector.
-        self.0.push(guest.clone());
+        self.guests.push(guest.clone());
 
         // Returns the cloned guest as a Result.
         Ok(guest)
@@ -97,12 +125,16 @@ impl FrontendGuests {
     /// # Arguments
     ///
     /// * `guest_id` - The Guest ID of the guest to be removed.
-    fn remove(&mut self, guest_id: u16) {
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the guest exited cleanly, otherwise the first error raised during its teardown.
+    fn remove(&mut self, guest_id: u16) -> Result<()> {
         // Finds the position of the guest with the provided Guest ID in the internal vector
         // and removes it from the vector, then calls its `exit()` method.
-        self.0
+        self.guests
             .remove(
-                self.0
+                self.guests
                     .iter()
                     .position(|guest| guest.id == guest_id)
                     .unwrap(),
@@ -110,51 +142,63 @@ impl FrontendGuests {
             .exit()
     }
 
-    /// Adds a device to the guest.
-    /// If the guest does not exist, creates a new guest and adds the device to it.
+    /// Starts the addition of a device to the guest.
+    /// If the guest does not exist, creates a new guest for the device.
+    /// The guest is kept even if it has no devices until the addition ends.
     ///
     /// # Arguments
     ///
+    /// * `frontend_id` - The Frontend ID of the frontend the guest belongs to.
     /// * `guest_id` - The Guest ID of the guest to which the device will be added.
-    /// * `dev_id` - The Device ID of the device to be added.
-    /// * `dev_irq` - The Device IRQ of the device to be added.
-    /// * `dev_addr` - The Device address of the device to be added.
     /// * `ram_addr` - The RAM base address of the guest to which the device will be added.
     /// * `ram_size` - The RAM size of the guest to which the device will be added.
-    /// * `shmem_path` - The shared memory path of the guest to which the device will be added.
-    /// * `socket_path` - The socket path of the guest to which the device will be added.
     ///
     /// # Returns
     ///
-    /// * `Result<Arc<BaoDevice>>` - A cloned Arc to the newly created device as a Result.
-    fn add_device(
+    /// * `DeviceResult<Arc<BaoGuest>>` - A cloned Arc to the guest to which the device will be
+    ///   added, or Exiting if the frontend is exiting.
+    fn begin_add(
         &mut self,
+        frontend_id: u16,
         guest_id: u16,
-        dev_id: u64,
-        dev_irq: u64,
-        dev_addr: u64,
         ram_addr: u64,
         ram_size: u64,
-        shmem_path: String,
-        socket_path: String,
-    ) -> Result<Arc<BaoDevice>> {
-        // Attempts to find the guest with the provided Guest ID.
-        // If found, adds the device to that guest; otherwise, creates a new guest and adds the device.
+    ) -> DeviceResult<Arc<BaoGuest>> {
+        // Refuses devices that would be added behind the teardown of the guests.
+        if self.exiting {
+            return Err(DeviceError::Exiting);
+        }
+
+        // Attempts to find the guest with the provided Guest ID, otherwise creates a new guest.
         let guest = match self.find(guest_id) {
             Some(guest) => guest,
-            None => self.add(guest_id, ram_addr, ram_size)?,
+            None => self.add(frontend_id, guest_id, ram_addr, ram_size)?,
         };
 
-        // Delegates the addition of the device to the found or newly created guest.
-        guest.add_device(
-            dev_id,
-            dev_irq,
-            dev_addr,
-            ram_addr,
-            ram_size,
-            shmem_path,
-            socket_path,
-        )
+        *self.adding.entry(guest_id).or_default() += 1;
+
+        Ok(guest)
+    }
+
+    /// Ends the addition of a device to the guest with the given Guest ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The Guest ID of the guest to which the device was added.
+    fn end_add(&mut self, guest_id: u16) {
+        if let Some(adding) = self.adding.get_mut(&guest_id) {
+            *adding -= 1;
+            if *adding == 0 {
+                self.adding.remove(&guest_id);
+            }
+        }
+
+        // Removes a guest left without devices by failed additions.
+        if let Some(guest) = self.find(guest_id) {
+            if guest.is_empty() && !self.adding.contains_key(&guest_id) {
+                let _ = self.remove(guest_id);
+            }
+        }
     }
 
     /// Removes a device from the guest with the given Guest ID.
@@ -163,17 +207,24 @@ impl FrontendGuests {
     ///
     /// * `guest_id` - The Guest ID of the guest from which the device will be removed.
     /// * `dev_addr` - The Device address of the device to be removed.
-    fn remove_device(&mut self, guest_id: u16, dev_addr: u64) {
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device was removed, otherwise DeviceNotFound.
+    fn remove_device(&mut self, guest_id: u16, dev_addr: u64) -> Result<()> {
         // Finds the guest with the provided Guest ID.
-        let guest = self.find(guest_id).unwrap();
+        let guest = self.find(guest_id).ok_or(Error::DeviceNotFound)?;
 
         // Removes the device with the provided device ID from the guest.
-        guest.remove_device(dev_addr);
+        let ret = guest.remove_device(dev_addr);
 
-        // Checks if the guest is empty after device removal and removes the guest if so.
-        if guest.is_empty() {
-            self.remove(guest_id);
+        // Checks if the guest is empty after device removal and removes the guest if so,
+        // unless a device is being added to it.
+        if guest.is_empty() && !self.adding.contains_key(&guest_id) {
+            return ret.and(self.remove(guest_id));
         }
+
+        ret
     }
 }
 
@@ -182,9 +233,11 @@ impl FrontendGuests {
 /// # Attributes
 ///
 /// * `guests` - The guests of the frontend.
+/// * `added` - A Condvar signaled whenever a device addition ends.
 /// * `threads` - The threads of the frontend.
 pub struct BaoFrontend {
     guests: Mutex<FrontendGuests>,
+    added: Condvar,
     threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
@@ -194,6 +247,7 @@ impl BaoFrontend {
         // Creates a new instance of BaoFrontend wrapped in an Arc
         Ok(Arc::new(Self {
             guests: Mutex::new(FrontendGuests::default()), // Initializes FrontendGuests with default values and wraps it in a Mutex
+            added: Condvar::new(), // Initializes the device addition Condvar
             threads: Mutex::new(Vec::new()), // Initializes an empty Vec and wraps it in a Mutex
         }))
     }
@@ -203,6 +257,8 @@ impl BaoFrontend {
     ///
     /// # Arguments
     ///
+    /// * `frontend_id` - The Frontend ID of the frontend the guest belongs to, used if the guest
+    ///   is created.
     /// * `guest_id` - The Guest ID of the guest to which the device will be added.
     /// * `dev_id` - The Device ID of the device to be added.
     /// * `dev_irq` - The Device IRQ of the device to be added.
@@ -211,14 +267,16 @@ impl BaoFrontend {
     /// * `ram_size` - The RAM size of the guest to which the device will be added.
     /// * `shmem_path` - The shared memory path of the guest to which the device will be added.
     /// * `socket_path` - The socket path of the guest to which the device will be added.
+    /// * `options` - The options of the device to be added.
     ///
     /// # Returns
     ///
-    /// * `Result<()>` - Ok if the device was added successfully, otherwise an error.
+    /// * `DeviceResult<()>` - Ok if the device was added successfully, otherwise an error.
     ///
     /// # Examples
     ///
     /// ```
+    /// const FRONTEND_ID: u16 = 0;
     /// const GUEST_ID: u16 = 0;
     /// const DEV_ID: u64 = 4; // rng
     /// const DEV_IRQ: u64 = 0x2f;
@@ -230,10 +288,11 @@ impl BaoFrontend {
     ///
     /// let frontend = BaoFrontend::new().unwrap();
     /// let fe: std::sync::Arc<BaoFrontend> = frontend.clone();
-    /// fe.add_device(GUEST_ID, DEV_ID, DEV_IRQ, DEV_ADDR, RAM_ADDR, RAM_SIZE, SHMEM_PATH, SOCKET_PATH).unwrap();
+    /// fe.add_device(FRONTEND_ID, GUEST_ID, DEV_ID, DEV_IRQ, DEV_ADDR, RAM_ADDR, RAM_SIZE, SHMEM_PATH, SOCKET_PATH, DeviceOptions::default()).unwrap();
     /// ```
     pub fn add_device(
         &self,
+        frontend_id: u16,
         guest_id: u16,
         dev_id: u64,
         dev_irq: u64,
@@ -242,10 +301,17 @@ impl BaoFrontend {
         ram_size: u64,
         shmem_path: String,
         socket_path: String,
-    ) -> Result<()> {
-        // Adds a device for the given guest_id and dev_id to the guests using a Mutex lock
-        let dev = self.guests.lock().unwrap().add_device(
-            guest_id,
+        options: DeviceOptions,
+    ) -> DeviceResult<()> {
+        // Finds or creates the guest for the given guest_id using a Mutex lock
+        let guest =
+            self.guests
+                .lock()
+                .unwrap()
+                .begin_add(frontend_id, guest_id, ram_addr, ram_size)?;
+
+        // Adds the device without holding the lock, as connecting to the backend blocks
+        let ret = guest.clone().add_device(
             dev_id,
             dev_irq,
             dev_addr,
@@ -253,13 +319,19 @@ impl BaoFrontend {
             ram_size,
             shmem_path,
             socket_path,
-        )?;
+            options,
+        );
 
         // Enable the guest to receive I/O events
-        dev.guest.enable_io_events();
+        if ret.is_ok() {
+            guest.enable_io_events();
+        }
+
+        self.guests.lock().unwrap().end_add(guest_id);
+        self.added.notify_all();
 
-        // Returns Ok
-        Ok(())
+        // Returns Ok if the device was added
+        ret.map(|_| ())
     }
 
     /// Removes a device from the Frontend with the given Guest ID and device ID.
@@ -269,6 +341,10 @@ impl BaoFrontend {
     /// * `guest_id` - The Guest ID of the guest from which the device will be removed.
     /// * `dev_addr` - The Device address of the device to be removed.
     ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device was removed successfully, otherwise an error.
+    ///
     /// # Examples
     ///
     /// ```
@@ -277,14 +353,95 @@ impl BaoFrontend {
     ///
     /// let frontend = BaoFrontend::new().unwrap();
     /// let fe: std::sync::Arc<BaoFrontend> = frontend.clone();
-    /// fe.remove_device(GUEST_ID, DEV_ADDR);
+    /// fe.remove_device(GUEST_ID, DEV_ADDR).unwrap();
     /// ```
-    pub fn remove_device(&self, guest_id: u16, dev_addr: u64) {
+    pub fn remove_device(&self, guest_id: u16, dev_addr: u64) -> Result<()> {
         // Removes a device for the given fe_id and dev_id from the guests using a Mutex lock
         self.guests
             .lock()
             .unwrap()
-            .remove_device(guest_id, dev_addr);
+            .remove_device(guest_id, dev_addr)
+    }
+
+    /// Returns the guests of the Frontend.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Arc<BaoGuest>>` - Cloned Arcs to the guests.
+    pub fn guests(&self) -> Vec<Arc<BaoGuest>> {
+        self.guests.lock().unwrap().guests.clone()
+    }
+
+    /// Finds a guest of the Frontend by Guest ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The Guest ID of the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<BaoGuest>>` - A cloned Arc to the found guest or None if not found.
+    pub fn guest(&self, guest_id: u16) -> Option<Arc<BaoGuest>> {
+        self.guests.lock().unwrap().find(guest_id)
+    }
+
+    /// Finds a device of the Frontend by Guest ID and device address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The Guest ID of the guest owning the device.
+    /// * `dev_addr` - The Device address of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<BaoDevice>>` - A cloned Arc to the found device or None if not found.
+    pub fn device(&self, guest_id: u16, dev_addr: u64) -> Option<Arc<BaoDevice>> {
+        self.guest(guest_id)?
+            .devices()
+            .into_iter()
+            .find(|dev| dev.addr == dev_addr)
+    }
+
+    /// Exits the Frontend by removing all of its guests.
+    /// New devices are refused and the device additions in flight (including the ones of the
+    /// Frontend threads) are waited for first, so that no device outlives the teardown.
+    /// Each guest then stops its I/O thread, exits its devices and destroys its device model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if every guest exited cleanly, otherwise the first error raised during the teardown.
+    pub fn exit(&self) -> Result<()> {
+        // Refuses new devices and cancels the device additions waiting for their backend
+        {
+            let mut guests = self.guests.lock().unwrap();
+            guests.exiting = true;
+            for guest in guests.guests.iter() {
+                guest.cancel_additions();
+            }
+        }
+
+        // Waits for the Frontend threads, which now fail to add their remaining devices
+        self.join_threads();
+
+        // Waits for the device additions still in flight (e.g. requested through the control socket)
+        let mut guests = self
+            .added
+            .wait_while(self.guests.lock().unwrap(), |guests| {
+                !guests.adding.is_empty()
+            })
+            .unwrap();
+        let mut ret = Ok(());
+
+        // Removes every guest, carrying on with the remaining ones on failure
+        while let Some(guest) = guests.guests.first() {
+            let (frontend_id, guest_id) = (guest.frontend_id, guest.id);
+            if let Err(err) = guests.remove(guest_id) {
+                error!(target: &guest_target(frontend_id, guest_id), "teardown failed: {:?}", err);
+                ret = ret.and(Err(err));
+            }
+        }
+
+        ret
     }
 
     /// Pushes a JoinHandle to the Frontend threads.
@@ -312,7 +469,7 @@ impl BaoFrontend {
     ///         .spawn(move || {
     ///             match fe.add_device(GUEST_ID, DEV_ID, DEV_IRQ, DEV_ADDR, RAM_ADDR, RAM_SIZE) {
     ///                 Ok(_) => { }
-    ///                 Err(err) => { fe.remove_device(GUEST_ID, DEV_ADDR); }
+    ///                 Err(err) => { error!("{:?}", err); }
     ///             }
     ///         })
     ///         .unwrap(),
@@ -322,15 +479,25 @@ impl BaoFrontend {
         // Pushes a JoinHandle to the threads using a Mutex lock
         self.threads.lock().unwrap().push(handle)
     }
+
+    /// Joins all handles from the threads vector.
+    fn join_threads(&self) {
+        // Loops until all handles are popped from the threads vector
+        loop {
+            // Pops the handle without holding the lock while joining the thread
+            let handle = self.threads.lock().unwrap().pop();
+            match handle {
+                // Joins the thread represented by the handle
+                Some(handle) => handle.join().unwrap(),
+                None => break,
+            }
+        }
+    }
 }
 
 impl Drop for BaoFrontend {
     /// Drops all handles from the threads vector.
     fn drop(&mut self) {
-        // Loops until all handles are popped from the threads vector
-        while let Some(handle) = self.threads.lock().unwrap().pop() {
-            // Joins the thread represented by the handle
-            handle.join().unwrap();
-        }
+        self.join_threads();
     }
 }
@@ -26,28 +26,93 @@
 //!
 //!│   └── ... (more devices)
 //!
-//!└── I/O Event Handling Thread
+//!├── I/O Event Handling Thread
+//!
+//!└── State
+//!
+//! # Lifecycle:
+//!
+//! Created ──> Running <──> Paused
+//!
+//! Created / Running / Paused ──> Stopping ──> Stopped
+//!
+//! Running ──> Failed ──> Stopping ──> Stopped
 
 use std::{
-    sync::{Arc, Mutex},
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread::{Builder, JoinHandle},
 };
 
-use super::{device::BaoDevice, devicemodel::BaoDeviceModel};
+use super::{
+    config::DeviceOptions,
+    device::BaoDevice,
+    devicemodel::{BaoDeviceModel, DeviceModel},
+    error::{DeviceError, DeviceResult},
+    logger::guest_target,
+    mmio::register_name,
+};
 use bao_sys::{defines::*, error::*, types::*};
+use log::{debug, error, info, warn};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-/// Represents a collection of BaoDevices.
+/// Represents a collection of BaoDevices, ordered by the base address of their MMIO windows.
+/// Since windows never overlap, the device containing an address is the one with the greatest
+/// base address not above it, which makes the lookup O(log n).
 #[derive(Default)]
-struct GuestDevices(Vec<Arc<BaoDevice>>);
+struct GuestDevices(BTreeMap<u64, Arc<BaoDevice>>);
 
 impl GuestDevices {
-    /// Pushes a new device to the collection.
+    /// Checks that a MMIO window does not overlap the window of any device of the collection.
     ///
     /// # Arguments
     ///
-    /// * `dev` - A cloned Arc of the BaoDevice to be pushed to the collection.
-    fn push(&mut self, dev: Arc<BaoDevice>) {
-        self.0.push(dev);
+    /// * `addr` - The base address of the window.
+    /// * `size` - The size of the window.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceResult<()>` - Ok(()) if the window is free, InvalidMmioAddr if it is empty or wraps
+    ///   around the address space, or OverlappingDevice if it overlaps the window of a device.
+    fn check(&self, addr: u64, size: u64) -> DeviceResult<()> {
+        // Reject empty windows and windows wrapping around the address space
+        let end = addr
+            .checked_add(size)
+            .filter(|_| size != 0)
+            .ok_or(Error::InvalidMmioAddr("device window", addr))?;
+
+        // The closest device below must end before the window starts, and the closest device
+        // above must start after the window ends
+        let overlapped = self
+            .find(addr)
+            .or_else(|| self.0.range(addr..end).next().map(|(_, dev)| dev.clone()));
+        if let Some(dev) = overlapped {
+            return Err(DeviceError::OverlappingDevice {
+                addr: dev.addr,
+                size: dev.size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Inserts a new device in the collection.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - A cloned Arc of the BaoDevice to be inserted in the collection.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceResult<()>` - Ok(()) on success, or OverlappingDevice if the device window overlaps another one.
+    fn insert(&mut self, dev: Arc<BaoDevice>) -> DeviceResult<()> {
+        self.check(dev.addr, dev.size)?;
+        self.0.insert(dev.addr, dev);
+        Ok(())
     }
 
     /// Removes a BaoDevice with the specified device address from the collection.
@@ -58,35 +123,36 @@ impl GuestDevices {
     ///
     /// # Returns
     ///
-    /// * `Arc<BaoDevice>` - The removed BaoDevice wrapped in an Arc.
-    fn remove(&mut self, dev_addr: u64) -> Arc<BaoDevice> {
-        // Find the index of the device with the specified dev_id
-        let index = self.0.iter().position(|dev| dev.addr == dev_addr).unwrap();
-
-        // Remove the device from the vector and return it wrapped in an Arc
-        self.0.remove(index)
+    /// * `Result<Arc<BaoDevice>>` - The removed BaoDevice wrapped in an Arc, or DeviceNotFound.
+    fn remove(&mut self, dev_addr: u64) -> Result<Arc<BaoDevice>> {
+        self.0.remove(&dev_addr).ok_or(Error::DeviceNotFound)
     }
 
-    /// Handles I/O events for the BaoDevice collection based on the given BaoIoRequest.
-    /// If the request address falls within a device's address range, triggers the corresponding device's io_event.
-    fn io_event(&self, req: &mut BaoIoRequest) -> Result<()> {
-        for dev in &self.0 {
-            // Check if the request address falls within the address range of the current device
-            if req.addr >= dev.addr && req.addr < dev.addr + VIRTIO_MMIO_IO_SIZE {
-                // Trigger the io_event for the device and return Ok(()) on success
-                match dev.io_event(req) {
-                    Ok(()) => {
-                        return Ok(());
-                    }
-                    Err(err) => {
-                        return Err(err);
-                    }
-                }
-            }
-        }
+    /// Returns the devices of the collection, ordered by address.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Arc<BaoDevice>>` - Cloned Arcs to the devices.
+    fn devices(&self) -> Vec<Arc<BaoDevice>> {
+        self.0.values().cloned().collect()
+    }
 
-        // Device not found
-        Err(Error::DeviceNotFound)
+    /// Finds the BaoDevice whose MMIO window contains the given address.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The accessed address.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<BaoDevice>>` - A cloned Arc to the found device or None if not found.
+    fn find(&self, addr: u64) -> Option<Arc<BaoDevice>> {
+        self.0
+            .range(..=addr)
+            .next_back()
+            .map(|(_, dev)| dev)
+            .filter(|dev| addr - dev.addr < dev.size)
+            .cloned()
     }
 
     /// Checks if the BaoDevice collection is empty.
@@ -99,21 +165,62 @@ impl GuestDevices {
     }
 }
 
+/// Represents the lifecycle state of a BaoGuest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestState {
+    /// The guest exists but was not enabled to process I/O events yet.
+    Created,
+    /// The guest's thread is processing I/O events.
+    Running,
+    /// The guest's thread holds off I/O events until the guest is resumed.
+    Paused,
+    /// The guest is being torn down.
+    Stopping,
+    /// The guest was torn down.
+    Stopped,
+    /// The guest's thread stopped processing I/O events due to an error.
+    Failed,
+}
+
+impl fmt::Display for GuestState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GuestState::Created => "created",
+            GuestState::Running => "running",
+            GuestState::Paused => "paused",
+            GuestState::Stopping => "stopping",
+            GuestState::Stopped => "stopped",
+            GuestState::Failed => "failed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 /// Represents a BaoGuest.
 ///
 /// # Attributes
 ///
+/// * `frontend_id` - The ID of the frontend the guest belongs to.
 /// * `id` - The ID of the guest.
-/// * `dm` - A Mutex-protected BaoDeviceModel instance.
+/// * `dm` - The device model used to interact with the I/O Request Management System.
 /// * `devices` - A Mutex-protected collection of guest devices.
 /// * `handle` - A Mutex-protected handle for the guest's thread to process the I/O events.
-/// * `enabled` - A Mutex-protected boolean indicating whether the guest is enabled.
+/// * `state` - A Mutex-protected lifecycle state of the guest.
+/// * `state_cond` - A Condvar signaled on every state change.
+/// * `errors` - The number of faulty accesses completed on behalf of the devices.
+/// * `target` - The log target of the guest.
+/// * `cancel` - The EventFd signaled to cancel the device additions waiting for their backend.
 pub struct BaoGuest {
+    pub frontend_id: u16,
     pub id: u16,
-    pub dm: Mutex<BaoDeviceModel>,
+    pub dm: Arc<dyn DeviceModel>,
     devices: Mutex<GuestDevices>,
     handle: Mutex<Option<JoinHandle<Result<()>>>>,
-    enabled: Mutex<bool>,
+    state: Mutex<GuestState>,
+    state_cond: Condvar,
+    errors: AtomicU64,
+    target: String,
+    pub cancel: EventFd,
 }
 
 // Implementing `Send` trait unsafely for `BaoGuest`.
@@ -132,6 +239,7 @@ impl BaoGuest {
     ///
     /// # Arguments
     ///
+    /// * `frontend_id` - The ID of the frontend the guest belongs to.
     /// * `id` - The ID of the guest.
     /// * `ram_addr` - The address of the guest's RAM.
     /// * `ram_size` - The size of the guest's RAM.
@@ -139,7 +247,7 @@ impl BaoGuest {
     /// # Returns
     ///
     /// * `Result<Arc<Self>>` - A Result containing an Arc-wrapped BaoGuest instance on success, or an Error on failure.
-    pub fn new(id: u16, ram_addr: u64, ram_size: u64) -> Result<Arc<Self>> {
+    pub fn new(frontend_id: u16, id: u16, ram_addr: u64, ram_size: u64) -> Result<Arc<Self>> {
         // Create a new BaoDeviceModel instance
         let dm = match BaoDeviceModel::new(id, ram_addr, ram_size) {
             Ok(dm) => dm,
@@ -148,13 +256,37 @@ impl BaoGuest {
             }
         };
 
+        Self::with_device_model(frontend_id, id, Arc::new(dm))
+    }
+
+    /// Creates a new instance of BaoGuest with the given Guest ID on top of the given device model.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - The ID of the frontend the guest belongs to.
+    /// * `id` - The ID of the guest.
+    /// * `dm` - The device model used to interact with the I/O Request Management System.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Arc<Self>>` - A Result containing an Arc-wrapped BaoGuest instance on success, or an Error on failure.
+    pub fn with_device_model(
+        frontend_id: u16,
+        id: u16,
+        dm: Arc<dyn DeviceModel>,
+    ) -> Result<Arc<Self>> {
         // Creates a new BaoGuest with the given Frontend ID.
         let guest = Arc::new(Self {
+            frontend_id,                                  // Assigns the given Frontend ID
             id,                                           // Assigns the given ID
-            dm: Mutex::new(dm), // Initializes dm as a Mutex wrapping the newly created BaoDeviceModel
+            dm,                                           // Assigns the given device model
             devices: Mutex::new(GuestDevices::default()), // Initializes devices with default GuestDevices and wraps it in a Mutex
             handle: Mutex::new(None), // Initializes handle as a Mutex wrapping None
-            enabled: Mutex::new(false), // Initializes enabled as a Mutex wrapping false
+            state: Mutex::new(GuestState::Created), // Initializes the state as Created
+            state_cond: Condvar::new(), // Initializes the state Condvar
+            errors: AtomicU64::new(0), // Initializes the faulty accesses counter
+            target: guest_target(frontend_id, id), // Initializes the log target
+            cancel: EventFd::new(EFD_NONBLOCK).unwrap(), // Initializes the cancellation EventFd
         });
 
         // Creates a pointer to the same guest reference and sets up the I/O event handling thread for the BaoGuest I/O events.
@@ -181,10 +313,11 @@ impl BaoGuest {
     /// * `ram_size` - The size of the guest's RAM.
     /// * `shmem_path` - The path to the shared memory driver.
     /// * `socket_path` - The path to the socket associated with the device to be added.
+    /// * `options` - The options of the device to be added.
     ///
     /// # Returns
     ///
-    /// * `Result<Arc<BaoDevice>>` - A Result containing an Arc-wrapped BaoDevice instance on success, or an Error on failure.
+    /// * `DeviceResult<Arc<BaoDevice>>` - A Result containing an Arc-wrapped BaoDevice instance on success, or an Error on failure.
     pub fn add_device(
         self: Arc<Self>,
         dev_id: u64,
@@ -194,7 +327,14 @@ impl BaoGuest {
         ram_size: u64,
         shmem_path: String,
         socket_path: String,
-    ) -> Result<Arc<BaoDevice>> {
+        options: DeviceOptions,
+    ) -> DeviceResult<Arc<BaoDevice>> {
+        // Reject windows overlapping another device before connecting to the backend
+        self.devices
+            .lock()
+            .unwrap()
+            .check(dev_addr, options.mmio_size)?;
+
         // Create a new BaoDevice associated with this BaoGuest instance
         let dev = BaoDevice::new(
             dev_id,
@@ -204,14 +344,26 @@ impl BaoGuest {
             ram_size,
             shmem_path,
             socket_path,
+            options,
             self.clone(),
         )?;
 
-        // Acquire a lock on the devices Mutex and push the newly created device into the collection
-        self.devices.lock().unwrap().push(dev.clone()); // Locks the Mutex, pushes the device, and clones the device to keep the Arc reference count consistent
+        // Acquire a lock on the devices Mutex and insert the newly created device into the collection
+        let ret = self.devices.lock().unwrap().insert(dev.clone()); // Locks the Mutex, inserts the device, and clones the device to keep the Arc reference count consistent
+        if let Err(err) = ret {
+            // Another device took the window meanwhile
+            let _ = dev.exit();
+            return Err(err);
+        }
 
-        // Print a message indicating the creation of the device along with the Frontend ID and device ID
-        println!("Created device {} / {}", self.id, dev_id);
+        info!(
+            target: &dev.target,
+            "created device id={} irq=0x{:x} addr=0x{:x} size=0x{:x}",
+            dev_id,
+            dev_irq,
+            dev_addr,
+            dev.size
+        );
 
         // Return the newly created BaoDevice wrapped in an Arc
         Ok(dev)
@@ -222,34 +374,169 @@ impl BaoGuest {
     /// # Arguments
     ///
     /// * `dev_addr` - The address of the device to be removed.
-    pub fn remove_device(&self, dev_addr: u64) {
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, DeviceNotFound if there is no such device,
+    ///   or the first Error raised while tearing the device down.
+    pub fn remove_device(&self, dev_addr: u64) -> Result<()> {
         // Attempt to remove the device with the specified dev_addr from the devices collection
-        let dev = self.devices.lock().unwrap().remove(dev_addr); // Locks the Mutex, removes the device with the given dev_addr, and returns it
+        let dev = self.devices.lock().unwrap().remove(dev_addr)?; // Locks the Mutex, removes the device with the given dev_addr, and returns it
 
-        // Print a message indicating the removal of the device along with the Frontend ID and device ID
-        println!("Removed device {} at 0x{:x}", self.id, dev_addr);
+        info!(target: &dev.target, "removed device id={} addr=0x{:x}", dev.id, dev_addr);
 
         // Call the exit method of the removed device to perform any necessary cleanup or exit actions
-        dev.exit(); // Invokes the exit method of the removed device
+        dev.exit() // Invokes the exit method of the removed device
+    }
+
+    /// Returns the devices of the BaoGuest.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Arc<BaoDevice>>` - Cloned Arcs to the devices.
+    pub fn devices(&self) -> Vec<Arc<BaoDevice>> {
+        self.devices.lock().unwrap().devices()
     }
 
     /// Handles I/O events for the BaoGuest based on the given request.
+    /// Faulty accesses never stop the I/O event handling thread: they are completed as
+    /// reads-as-zero / writes-ignored, as the virtio-mmio transport does for undefined registers.
     ///
     /// # Arguments
     ///
     /// * `req` - A mutable reference to a BaoIoRequest.
+    fn io_event(&self, req: &mut BaoIoRequest) {
+        // Find the device without holding the devices lock while the request is handled
+        let dev = self.devices.lock().unwrap().find(req.addr);
+
+        let ret = match &dev {
+            Some(dev) => dev.io_event(req),
+            None => Err(Error::DeviceNotFound),
+        };
+
+        if let Err(err) = ret {
+            self.io_error(req, dev.as_deref(), err);
+        }
+    }
+
+    /// Completes a faulty access as reads-as-zero / writes-ignored and accounts for it.
+    /// If the device was configured so, it is also moved to DEVICE_NEEDS_RESET.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - A mutable reference to the faulty BaoIoRequest.
+    /// * `dev` - The accessed device, if any.
+    /// * `err` - The Error raised while handling the request.
+    fn io_error(&self, req: &mut BaoIoRequest, dev: Option<&BaoDevice>, err: Error) {
+        let op = if req.op == BAO_IO_WRITE {
+            "write"
+        } else {
+            "read"
+        };
+
+        let target = dev.map_or(self.target.as_str(), |dev| dev.target.as_str());
+        warn!(
+            target: target,
+            "faulty {} reg={} offset=0x{:x} addr=0x{:x} width={}: {:?}",
+            op,
+            register_name(req.reg_off),
+            req.reg_off,
+            req.addr,
+            req.access_width,
+            err
+        );
+
+        // Reads-as-zero / writes-ignored
+        if req.op == BAO_IO_READ {
+            req.value = 0;
+        }
+
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(dev) = dev {
+            dev.errors.fetch_add(1, Ordering::Relaxed);
+            if dev.options.needs_reset_on_error {
+                dev.set_needs_reset();
+            }
+        }
+    }
+
+    /// Returns the number of faulty accesses completed on behalf of the devices of the BaoGuest.
     ///
     /// # Returns
     ///
-    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    fn io_event(&self, req: &mut BaoIoRequest) -> Result<()> {
-        self.devices.lock().unwrap().io_event(req)
+    /// * `u64` - The number of faulty accesses.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the lifecycle state of the BaoGuest.
+    ///
+    /// # Returns
+    ///
+    /// * `GuestState` - The current state.
+    pub fn state(&self) -> GuestState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves the BaoGuest to a new state if the current state is one of the given states.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The states allowed to transition.
+    /// * `to` - The new state.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the transition took place, false otherwise.
+    fn transition(&self, from: &[GuestState], to: GuestState) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !from.contains(&state) {
+            return false;
+        }
+        debug!(target: &self.target, "state {} -> {}", *state, to);
+        *state = to;
+        // Wake up the I/O event handling thread waiting for a state change
+        self.state_cond.notify_all();
+        true
     }
 
     /// Enables the BaoGuest to start processing I/O events.
     pub fn enable_io_events(&self) {
-        // Acquire a lock on the enabled Mutex and set the boolean to true
-        *self.enabled.lock().unwrap() = true;
+        self.transition(&[GuestState::Created], GuestState::Running);
+    }
+
+    /// Pauses the processing of I/O events. Requests already being handled are completed, while
+    /// the following ones are held off until the guest is resumed.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the guest was running and is now paused, false otherwise.
+    pub fn pause(&self) -> bool {
+        self.transition(&[GuestState::Running], GuestState::Paused)
+    }
+
+    /// Resumes the processing of I/O events of a paused BaoGuest.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the guest was paused and is now running, false otherwise.
+    pub fn resume(&self) -> bool {
+        self.transition(&[GuestState::Paused], GuestState::Running)
+    }
+
+    /// Waits until the BaoGuest is allowed to process I/O events.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the guest is running, false if the I/O event handling thread must stop.
+    fn wait_running(&self) -> bool {
+        let state = self
+            .state_cond
+            .wait_while(self.state.lock().unwrap(), |state| {
+                matches!(state, GuestState::Created | GuestState::Paused)
+            })
+            .unwrap();
+        *state == GuestState::Running
     }
 
     /// Sets up the event handling thread for the BaoGuest.
@@ -258,6 +545,10 @@ impl BaoGuest {
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     fn setup_io_events(self: Arc<Self>) -> Result<()> {
+        // Create the I/O client before spawning the thread, so that `exit` can always destroy it
+        // to wake up the thread
+        self.dm.create_io_client()?;
+
         // Clone the BaoGuest instance
         let guest = self.clone();
 
@@ -266,50 +557,8 @@ impl BaoGuest {
             Builder::new()
                 .name(format!("guest {}", self.id))
                 .spawn(move || {
-                    // Create the I/O client
-                    match guest.dm.lock().unwrap().create_io_client() {
-                        Ok(()) => {}
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    }
-
-                    // Wait until the guest is enabled
-                    while !*guest.enabled.lock().unwrap() {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                    }
-
-                    // Guest loop
-                    loop {
-                        // Attach the I/O client
-                        match guest.dm.lock().unwrap().attach_io_client() {
-                            Ok(()) => {}
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                        // Request the I/O client
-                        let mut req = match guest.dm.lock().unwrap().request_io() {
-                            Ok(req) => req,
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        };
-                        // Call the io_event method to process I/O event for the guest
-                        match guest.io_event(&mut req) {
-                            Ok(()) => {}
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                        // Notify the I/O client that the I/O request has been completed
-                        match guest.dm.lock().unwrap().notify_io_completed(req) {
-                            Ok(()) => {}
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                    }
+                    let ret = guest.io_loop();
+                    guest.io_loop_exit(ret)
                 })
                 .unwrap()
                 .into(),
@@ -317,6 +566,55 @@ impl BaoGuest {
         Ok(())
     }
 
+    /// Processes I/O events until the guest stops or an error occurs.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) if the guest stopped, or an Error on failure.
+    fn io_loop(&self) -> Result<()> {
+        loop {
+            // Wait until the guest is running
+            if !self.wait_running() {
+                return Ok(());
+            }
+            // Attach the I/O client
+            self.dm.attach_io_client()?;
+            // Hold off the request while the guest is paused
+            if !self.wait_running() {
+                return Ok(());
+            }
+            // Request the I/O client
+            let mut req = self.dm.request_io()?;
+            // Call the io_event method to process I/O event for the guest
+            self.io_event(&mut req);
+            // Notify the I/O client that the I/O request has been completed
+            self.dm.notify_io_completed(req)?;
+        }
+    }
+
+    /// Computes the return value of the I/O event handling thread and records failures in the state.
+    /// Once the guest is stopping, the I/O client is destroyed under the thread, so failures are expected.
+    ///
+    /// # Arguments
+    ///
+    /// * `ret` - The return value of the I/O event loop.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok(()) if the guest is stopping, otherwise the given return value.
+    fn io_loop_exit(&self, ret: Result<()>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if *state == GuestState::Stopping {
+            return Ok(());
+        }
+        if ret.is_err() {
+            debug!(target: &self.target, "state {} -> {}", *state, GuestState::Failed);
+            *state = GuestState::Failed;
+            self.state_cond.notify_all();
+        }
+        ret
+    }
+
     /// Checks if the BaoGuest is empty (has no devices).
     ///
     /// # Returns
@@ -327,14 +625,70 @@ impl BaoGuest {
         self.devices.lock().unwrap().is_empty() // Locks the Mutex and checks if the devices collection is empty
     }
 
-    /// Exits the BaoGuest by joining the thread associated with the handle Mutex and destroying the device model.
-    pub fn exit(&self) {
+    /// Cancels the device additions waiting for their backend, which then fail with Exiting.
+    pub fn cancel_additions(&self) {
+        let _ = self.cancel.write(1);
+    }
+
+    /// Exits the BaoGuest, tearing it down in order:
+    ///
+    /// 1. Moves the guest to Stopping and stops the I/O event handling thread by destroying the
+    ///    I/O client it is attached to.
+    /// 2. Exits the remaining devices (Generic reset/shutdown, irqfds and ioeventfds deassignment).
+    /// 3. Destroys the VirtIO backend of the device model.
+    ///
+    /// 4. Moves the guest to Stopped.
+    ///
+    /// Every step runs even if a previous one failed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or the first Error raised during the teardown.
+    pub fn exit(&self) -> Result<()> {
+        let mut ret = Ok(());
+
+        // Ask the I/O event handling thread to stop and wake it up by destroying the I/O client
+        self.transition(
+            &[
+                GuestState::Created,
+                GuestState::Running,
+                GuestState::Paused,
+                GuestState::Failed,
+            ],
+            GuestState::Stopping,
+        );
+        if let Err(err) = self.dm.destroy_io_client() {
+            ret = ret.and(Err(err));
+        }
+
         // Attempt to take the handle from the handle Mutex and join the associated thread if it exists
         if let Some(handle) = self.handle.lock().unwrap().take() {
-            let _ = handle.join().unwrap(); // Joins the thread associated with the handle if it exists
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    error!(target: &self.target, "I/O thread failed: {:?}", err);
+                }
+                Err(_) => {
+                    error!(target: &self.target, "I/O thread panicked");
+                }
+            }
+        }
+
+        // Exit the remaining devices
+        let devices = std::mem::take(&mut self.devices.lock().unwrap().0);
+        for dev in devices.into_values() {
+            if let Err(err) = dev.exit() {
+                ret = ret.and(Err(err));
+            }
         }
 
         // Destroy the device model
-        self.dm.lock().unwrap().destroy().unwrap();
+        if let Err(err) = self.dm.destroy() {
+            ret = ret.and(Err(err));
+        }
+
+        self.transition(&[GuestState::Stopping], GuestState::Stopped);
+
+        ret
     }
 }
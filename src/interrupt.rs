@@ -5,13 +5,29 @@
 
 //! The 'Interrupt' module serves as an abstraction to implement device interrupts
 //! functionalities in form of Irqfds.
+//!
+//! The backend is handed a dedicated vring call eventfd, which a forwarder thread turns into a
+//! used buffer notification: it flags the VIRTIO_MMIO_INT_VRING reason in the InterruptStatus
+//! register before signaling the irqfd, so the driver can read and acknowledge it. The frontend
+//! raises its own interrupts (e.g. configuration changes) through `BaoInterrupt::signal`, which
+//! flags their reason the same way.
+//!
+//! When the kernel module does not support irqfds (or the device `irqfd` option is off), the
+//! backend is also handed a configuration call eventfd, and the interrupts are injected through
+//! `notify_guest` instead of the irqfd.
 
-use super::device::BaoDevice;
+use super::device::{poll, BaoDevice};
 use bao_sys::{defines::*, error::*, types::*};
+use log::{info, warn};
+use std::io::{Error as IoError, ErrorKind};
 use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread::{Builder, JoinHandle};
 use std::{io::Result as IoResult, sync::Arc};
 use vhost_user_frontend::{VirtioInterrupt, VirtioInterruptType};
-use vmm_sys_util::eventfd::EventFd;
+use virtio_bindings::virtio_mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 /// Struct representing a BAO VirtIO interrupt
 ///
@@ -19,9 +35,22 @@ use vmm_sys_util::eventfd::EventFd;
 ///
 /// * `dev` - The BaoDevice associated with the interrupt.
 /// * `call` - The EventFd associated with the interrupt.
+/// * `status` - The pending interrupt reasons reported through the InterruptStatus register.
+/// * `vring_call` - The EventFd signaled by the backend on used buffer notifications.
+/// * `config_call` - The EventFd signaled by the backend on configuration change notifications,
+///   without irqfd.
+/// * `kill` - The EventFd used to stop the forwarder thread.
+/// * `forwarder` - The thread forwarding the backend notifications to the guest.
+/// * `irqfd` - Whether the irqfd is assigned, or interrupts go through `notify_guest`.
 pub struct BaoInterrupt {
     dev: Arc<BaoDevice>,
     call: EventFd,
+    status: AtomicU32,
+    vring_call: EventFd,
+    config_call: EventFd,
+    kill: EventFd,
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+    irqfd: bool,
 }
 
 impl BaoInterrupt {
@@ -38,34 +67,100 @@ impl BaoInterrupt {
         // Create a new EventFd for the interrupt
         let call = EventFd::new(0).unwrap();
 
-        // Create a new BaoInterrupt
-        let bao_int = Arc::new(BaoInterrupt {
-            dev,
-            call: call.try_clone().unwrap(),
-        });
-
         // Create a BaoIrqFd struct
         let irqfd = BaoIrqFd {
-            fd: bao_int.call.as_raw_fd() as i32,
+            fd: call.as_raw_fd() as i32,
             flags: BAO_IRQFD_FLAG_ASSIGN, // Assign the Irqfd
         };
 
-        // Create an Irqdf for the interrupt
-        match bao_int.dev.guest.dm.lock().unwrap().create_irqfd(irqfd) {
-            Ok(_) => (),
-            Err(err) => return Err(err),
-        }
+        // Create an Irqdf for the interrupt, unless the device opted out of it or the kernel
+        // module does not support it
+        let irqfd = dev.options.irqfd
+            && match dev.guest.dm.create_irqfd(irqfd) {
+                Ok(_) => true,
+                Err(Error::BaoIoctlError(err, _)) if err.raw_os_error() == Some(libc::ENOTTY) => {
+                    info!(target: &dev.target, "irqfd unavailable, using notify_guest");
+                    false
+                }
+                Err(err) => return Err(err),
+            };
+
+        // Create a new BaoInterrupt
+        let bao_int = Arc::new(BaoInterrupt {
+            dev,
+            call,
+            status: AtomicU32::new(0),
+            vring_call: EventFd::new(EFD_NONBLOCK).unwrap(),
+            config_call: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill: EventFd::new(EFD_NONBLOCK).unwrap(),
+            forwarder: Mutex::new(None),
+            irqfd,
+        });
+
+        // Forward the backend notifications to the guest, flagging their interrupt reason
+        let int = bao_int.clone();
+        *bao_int.forwarder.lock().unwrap() = Some(
+            Builder::new()
+                .name(format!("interrupt {:x}", bao_int.dev.addr))
+                .spawn(move || int.forward())
+                .unwrap(),
+        );
 
         // Return the BaoInterrupt
         Ok(bao_int)
     }
 
+    /// Forwards the backend notifications to the guest, flagging their interrupt reason,
+    /// until the BaoInterrupt exits.
+    fn forward(&self) {
+        loop {
+            let revents = poll(
+                &[
+                    (self.vring_call.as_raw_fd(), libc::POLLIN),
+                    (self.config_call.as_raw_fd(), libc::POLLIN),
+                    (self.kill.as_raw_fd(), libc::POLLIN),
+                ],
+                None,
+            );
+            if revents[2] != 0 {
+                return;
+            }
+
+            // Consume the notifications before flagging them, so none is missed
+            let mut reasons = 0;
+            if revents[0] != 0 && self.vring_call.read().is_ok() {
+                reasons |= VIRTIO_MMIO_INT_VRING;
+            }
+            if revents[1] != 0 && self.config_call.read().is_ok() {
+                self.dev.bump_config_generation();
+                reasons |= VIRTIO_MMIO_INT_CONFIG;
+            }
+
+            if reasons != 0 {
+                if let Err(err) = self.signal(reasons) {
+                    warn!(target: &self.dev.target, "interrupt injection failed: {}", err);
+                }
+            }
+        }
+    }
+
     /// Method to exit the BaoInterrupt.
     ///
     /// # Return
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     pub fn exit(&self) -> Result<()> {
+        // Stop forwarding the backend notifications
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            let _ = self.kill.write(1);
+            let _ = forwarder.join();
+        }
+
+        // Nothing else to do if the irqfd was not assigned
+        if !self.irqfd {
+            return Ok(());
+        }
+
         // Create a BaoIrqFd struct
         let irqfd = BaoIrqFd {
             fd: self.call.as_raw_fd() as i32,
@@ -73,7 +168,7 @@ impl BaoInterrupt {
         };
 
         // Destroy the Irqfd for the interrupt
-        match self.dev.guest.dm.lock().unwrap().create_irqfd(irqfd) {
+        match self.dev.guest.dm.create_irqfd(irqfd) {
             Ok(_) => (),
             Err(err) => return Err(err),
         }
@@ -81,6 +176,57 @@ impl BaoInterrupt {
         // Return Ok if everything went well
         Ok(())
     }
+
+    /// Method to inject the interrupt into the guest through the irqfd, or through
+    /// `notify_guest` if there is none.
+    ///
+    /// # Return
+    ///
+    /// * `IoResult<()>` - An IoResult containing Ok(()) on success, or an Error on failure.
+    pub fn notify(&self) -> IoResult<()> {
+        if self.irqfd {
+            return self.call.write(1);
+        }
+
+        self.dev
+            .guest
+            .dm
+            .notify_guest()
+            .map_err(|err| IoError::new(ErrorKind::Other, format!("{:?}", err)))
+    }
+
+    /// Method to flag interrupt reasons as pending and inject the interrupt into the guest.
+    /// It takes no lock, so it can be called while the device is handling an access.
+    ///
+    /// # Arguments
+    ///
+    /// * `reasons` - The interrupt reasons (VIRTIO_MMIO_INT_VRING and/or VIRTIO_MMIO_INT_CONFIG).
+    ///
+    /// # Return
+    ///
+    /// * `IoResult<()>` - An IoResult containing Ok(()) on success, or an Error on failure.
+    pub fn signal(&self, reasons: u32) -> IoResult<()> {
+        self.status.fetch_or(reasons, Ordering::AcqRel);
+        self.notify()
+    }
+
+    /// Method to clear interrupt reasons acknowledged by the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `reasons` - The acknowledged interrupt reasons.
+    pub fn ack(&self, reasons: u32) {
+        self.status.fetch_and(!reasons, Ordering::AcqRel);
+    }
+
+    /// Interrupt status getter.
+    ///
+    /// # Return
+    ///
+    /// * `u32` - The pending interrupt reasons raised through the frontend.
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::Acquire)
+    }
 }
 
 impl VirtioInterrupt for BaoInterrupt {
@@ -88,25 +234,35 @@ impl VirtioInterrupt for BaoInterrupt {
     ///
     /// # Arguments
     ///
-    /// * `_int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
+    /// * `int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
     ///
     /// # Return
     ///
     /// * `IoResult<()>` - An IoResult containing Ok(()) on success, or an Error on failure.
-    fn trigger(&self, _int_type: VirtioInterruptType) -> IoResult<()> {
-        Ok(())
+    fn trigger(&self, int_type: VirtioInterruptType) -> IoResult<()> {
+        match int_type {
+            VirtioInterruptType::Config => self.signal(VIRTIO_MMIO_INT_CONFIG),
+            VirtioInterruptType::Queue(_) => self.signal(VIRTIO_MMIO_INT_VRING),
+        }
     }
 
     /// Implementation of the notifier method of the VirtioInterrupt trait for BaoInterrupt.
     ///
     /// # Arguments
     ///
-    /// * `_int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
+    /// * `int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
     ///
     /// # Return
     ///
-    /// * `Option<EventFd>` - An Option containing the EventFd associated with the interrupt.
-    fn notifier(&self, _int_type: VirtioInterruptType) -> Option<EventFd> {
-        Some(self.call.try_clone().unwrap())
+    /// * `Option<EventFd>` - An Option containing the EventFd the backend signals for that type,
+    ///   or None if the notifications go through `trigger`.
+    fn notifier(&self, int_type: VirtioInterruptType) -> Option<EventFd> {
+        match int_type {
+            // The backend raises configuration changes through a CONFIG_CHANGE_MSG, which
+            // flags their reason
+            VirtioInterruptType::Config if self.irqfd => None,
+            VirtioInterruptType::Config => Some(self.config_call.try_clone().unwrap()),
+            VirtioInterruptType::Queue(_) => Some(self.vring_call.try_clone().unwrap()),
+        }
     }
 }
@@ -0,0 +1,498 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Logger' module implements the `log` backend of the frontend.
+//!
+//! Records carry their context in the target and in `key=value` fields of the message:
+//!
+//! - Guest records use the `frontend<id>::guest<id>` target (e.g. `frontend0::guest3`).
+//! - Device records use the `frontend<id>::guest<id>::device@<addr>` target
+//!   (e.g. `frontend0::guest3::device@a003e00`).
+//!
+//! The frontend ID is the one of the configuration frontend the guest was created for.
+//!
+//! # Configuration
+//!
+//! - `--log-level <directives>` (or `BAO_VHOST_LOG`) - Comma-separated filtering directives, each one being either a level
+//!   (the default level) or `<target>=<level>`, e.g. `info,frontend0::guest3=trace`. A directive
+//!   applies to its target and to every target below it (`frontend0::guest3` also covers the
+//!   devices of guest 3), the most specific directive winning. Defaults to `info`.
+//! - `--log-output <output>` (or `BAO_VHOST_LOG_OUTPUT`) - `stderr` (default), `file:<path>` or
+//!   `syslog[:<socket>]` (the socket defaults to `/dev/log`).
+//!
+//! The rest of the command line is parsed by `bao_sys::utils::parse_arguments`, which does not
+//! know about the logging options, so the frontend executes itself again without them, handing
+//! their values through the environment variables, which are otherwise only a fallback.
+//!
+//! The directives can be changed at runtime through `set_directives`, e.g. with the
+//! `log_level` command of the control socket.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable holding the filtering directives.
+pub const LOG_ENV: &str = "BAO_VHOST_LOG";
+/// Environment variable holding the log output.
+pub const LOG_OUTPUT_ENV: &str = "BAO_VHOST_LOG_OUTPUT";
+/// Command line option holding the filtering directives.
+pub const LOG_LEVEL_ARG: &str = "--log-level";
+/// Command line option holding the log output.
+pub const LOG_OUTPUT_ARG: &str = "--log-output";
+
+/// Default syslog socket.
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Syslog facility (daemon).
+const SYSLOG_FACILITY: u8 = 3;
+
+/// The installed logger.
+static LOGGER: OnceLock<BaoLogger> = OnceLock::new();
+
+/// Returns the log target of a guest.
+///
+/// # Arguments
+///
+/// * `frontend_id` - The Frontend ID of the frontend the guest belongs to.
+/// * `guest_id` - The Guest ID.
+///
+/// # Returns
+///
+/// * `String` - The log target.
+pub fn guest_target(frontend_id: u16, guest_id: u16) -> String {
+    format!("frontend{}::guest{}", frontend_id, guest_id)
+}
+
+/// Returns the log target of a device.
+///
+/// # Arguments
+///
+/// * `frontend_id` - The Frontend ID of the frontend the guest belongs to.
+/// * `guest_id` - The Guest ID of the guest owning the device.
+/// * `dev_addr` - The device address.
+///
+/// # Returns
+///
+/// * `String` - The log target.
+pub fn device_target(frontend_id: u16, guest_id: u16, dev_addr: u64) -> String {
+    format!(
+        "{}::device@{:x}",
+        guest_target(frontend_id, guest_id),
+        dev_addr
+    )
+}
+
+/// Represents a set of filtering directives.
+///
+/// # Attributes
+///
+/// * `default` - The level of the targets not matched by any directive.
+/// * `targets` - The per-target levels.
+#[derive(Debug, PartialEq)]
+struct Directives {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    /// Parses comma-separated filtering directives.
+    ///
+    /// # Arguments
+    ///
+    /// * `directives` - The directives string.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The directives, or a message describing the invalid directive.
+    fn parse(directives: &str) -> Result<Self, String> {
+        let mut parsed = Self {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        };
+
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            let parse_level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("invalid level '{}'", level))
+            };
+
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = parse_level(level)?;
+                    parsed.targets.retain(|(t, _)| t != target);
+                    parsed.targets.push((target.to_string(), level));
+                }
+                None => parsed.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Returns the level of a target, given by its most specific directive.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The log target.
+    ///
+    /// # Returns
+    ///
+    /// * `LevelFilter` - The level of the target.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| {
+                target == t
+                    || target
+                        .strip_prefix(t.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Returns the most verbose level of all directives.
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl std::fmt::Display for Directives {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Represents the destination of the log records.
+enum Output {
+    Stderr,
+    File(Mutex<File>),
+    Syslog(UnixDatagram),
+}
+
+impl Output {
+    /// Opens a log output.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - `stderr`, `file:<path>` or `syslog[:<socket>]`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self, String>` - The output, or a message if it cannot be opened.
+    fn open(output: &str) -> Result<Self, String> {
+        match output.split_once(':') {
+            None if output == "stderr" => Ok(Output::Stderr),
+            Some(("file", path)) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|file| Output::File(Mutex::new(file)))
+                .map_err(|err| format!("{}: {}", path, err)),
+            _ if output == "syslog" || output.starts_with("syslog:") => {
+                let path = output.strip_prefix("syslog:").unwrap_or(SYSLOG_SOCKET);
+                let socket = UnixDatagram::unbound().map_err(|err| err.to_string())?;
+                socket
+                    .connect(path)
+                    .map_err(|err| format!("{}: {}", path, err))?;
+                Ok(Output::Syslog(socket))
+            }
+            _ => Err(format!("invalid log output '{}'", output)),
+        }
+    }
+}
+
+/// Represents the logger of the frontend.
+///
+/// # Attributes
+///
+/// * `directives` - The filtering directives, which can be changed at runtime.
+/// * `output` - The destination of the log records.
+struct BaoLogger {
+    directives: RwLock<Directives>,
+    output: Output,
+}
+
+impl Log for BaoLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.directives.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match &self.output {
+            Output::Syslog(socket) => {
+                let severity = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let message = format!(
+                    "<{}>bao-vhost-frontend[{}]: {}: {}",
+                    SYSLOG_FACILITY * 8 + severity,
+                    std::process::id(),
+                    record.target(),
+                    record.args()
+                );
+                let _ = socket.send(message.as_bytes());
+            }
+            output => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let line = format!(
+                    "[{}.{:06} {:<5} {}] {}\n",
+                    now.as_secs(),
+                    now.subsec_micros(),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                let _ = match output {
+                    Output::File(file) => file.lock().unwrap().write_all(line.as_bytes()),
+                    _ => std::io::stderr().write_all(line.as_bytes()),
+                };
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.output {
+            Output::File(file) => {
+                let _ = file.lock().unwrap().flush();
+            }
+            Output::Stderr => {
+                let _ = std::io::stderr().flush();
+            }
+            Output::Syslog(_) => {}
+        }
+    }
+}
+
+/// Splits the logging options off the command line arguments. Both `--log-level <directives>`
+/// and `--log-level=<directives>` forms are accepted, and so are the ones of `--log-output`.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments, without the program name.
+///
+/// # Returns
+///
+/// * `Result<(Vec<(&'static str, OsString)>, Vec<OsString>), String>` - The environment variables
+///   standing for the logging options along with the remaining arguments, or a message describing
+///   the invalid option.
+fn split_args(
+    args: impl IntoIterator<Item = OsString>,
+) -> Result<(Vec<(&'static str, OsString)>, Vec<OsString>), String> {
+    let mut vars = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let option = [(LOG_LEVEL_ARG, LOG_ENV), (LOG_OUTPUT_ARG, LOG_OUTPUT_ENV)]
+            .into_iter()
+            .find_map(|(name, var)| {
+                let value = arg.to_str()?.strip_prefix(name)?;
+                match value.strip_prefix('=') {
+                    Some(value) => Some((name, var, Some(OsString::from(value)))),
+                    None if value.is_empty() => Some((name, var, None)),
+                    None => None,
+                }
+            });
+
+        match option {
+            Some((name, var, value)) => {
+                let value = value
+                    .or_else(|| args.next())
+                    .ok_or(format!("missing value for '{}'", name))?;
+                if var == LOG_ENV {
+                    Directives::parse(&value.to_string_lossy())?;
+                }
+                vars.push((var, value));
+            }
+            None => rest.push(arg),
+        }
+    }
+
+    Ok((vars, rest))
+}
+
+/// Applies the logging options of the command line, by executing the frontend again without
+/// them and with the environment variables standing for them, which take precedence over the
+/// inherited ones.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Ok(()) if the command line has no logging options, or a message
+///   describing why they could not be applied. It does not return otherwise.
+pub fn apply_args() -> Result<(), String> {
+    let mut args = std::env::args_os();
+    let program = args.next();
+    let (vars, rest) = split_args(args)?;
+    if vars.is_empty() {
+        return Ok(());
+    }
+
+    let exe = std::env::current_exe().map_err(|err| err.to_string())?;
+    let mut command = Command::new(exe);
+    if let Some(program) = program {
+        command.arg0(program);
+    }
+
+    Err(command.args(rest).envs(vars).exec().to_string())
+}
+
+/// Installs the logger configured by the `BAO_VHOST_LOG` and `BAO_VHOST_LOG_OUTPUT` environment
+/// variables, which the logging options of the command line are turned into by `apply_args`.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Ok(()) on success, or a message describing the invalid configuration.
+pub fn init() -> Result<(), String> {
+    let directives = Directives::parse(&std::env::var(LOG_ENV).unwrap_or_default())?;
+    let output =
+        Output::open(&std::env::var(LOG_OUTPUT_ENV).unwrap_or_else(|_| "stderr".to_string()))?;
+    let max_level = directives.max_level();
+
+    let logger = LOGGER.get_or_init(|| BaoLogger {
+        directives: RwLock::new(directives),
+        output,
+    });
+    log::set_logger(logger).map_err(|err| err.to_string())?;
+    log::set_max_level(max_level);
+
+    Ok(())
+}
+
+/// Replaces the filtering directives of the installed logger.
+///
+/// # Arguments
+///
+/// * `directives` - The new directives, e.g. `info,frontend0::guest3=trace`.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Ok(()) on success, or a message if the directives are invalid
+///   or no logger is installed.
+pub fn set_directives(directives: &str) -> Result<(), String> {
+    let logger = LOGGER.get().ok_or("logger not installed")?;
+    let directives = Directives::parse(directives)?;
+
+    log::set_max_level(directives.max_level());
+    *logger.directives.write().unwrap() = directives;
+
+    Ok(())
+}
+
+/// Returns the filtering directives of the installed logger.
+///
+/// # Returns
+///
+/// * `Option<String>` - The directives, or None if no logger is installed.
+pub fn directives() -> Option<String> {
+    LOGGER
+        .get()
+        .map(|logger| logger.directives.read().unwrap().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The most specific directive of a target applies, including to the targets below it.
+    #[test]
+    fn directives_levels() {
+        let directives = Directives::parse(
+            "warn,frontend0::guest3=trace,frontend0::guest3::device@a003e00=off,frontend1=debug",
+        )
+        .unwrap();
+
+        assert_eq!(directives.level(&guest_target(0, 1)), LevelFilter::Warn);
+        assert_eq!(directives.level(&guest_target(0, 3)), LevelFilter::Trace);
+        assert_eq!(directives.level(&guest_target(0, 30)), LevelFilter::Warn);
+        assert_eq!(directives.level(&guest_target(1, 3)), LevelFilter::Debug);
+        assert_eq!(
+            directives.level(&device_target(0, 3, 0xa003c00)),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            directives.level(&device_target(0, 3, 0xa003e00)),
+            LevelFilter::Off
+        );
+        assert_eq!(directives.max_level(), LevelFilter::Trace);
+        assert_eq!(
+            directives.to_string(),
+            "warn,frontend0::guest3=trace,frontend0::guest3::device@a003e00=off,frontend1=debug"
+        );
+    }
+
+    /// Invalid directives are rejected and the default level is `info`.
+    #[test]
+    fn directives_parse() {
+        assert_eq!(
+            Directives::parse("").unwrap().level(&guest_target(0, 0)),
+            LevelFilter::Info
+        );
+        assert!(Directives::parse("loud").is_err());
+        assert!(Directives::parse("guest0=loud").is_err());
+    }
+
+    /// The logging options are split off the command line, in either form, and stand for the
+    /// environment variables.
+    #[test]
+    fn split_logging_args() {
+        let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+
+        let (vars, rest) = split_args(argv(&[
+            "--config",
+            "frontend.yaml",
+            "--log-level",
+            "info,frontend0::guest3=trace",
+            "--log-output=file:/tmp/bao-vhost.log",
+        ]))
+        .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                (LOG_ENV, OsString::from("info,frontend0::guest3=trace")),
+                (LOG_OUTPUT_ENV, OsString::from("file:/tmp/bao-vhost.log")),
+            ]
+        );
+        assert_eq!(rest, argv(&["--config", "frontend.yaml"]));
+
+        let (vars, rest) = split_args(argv(&["--config", "frontend.yaml"])).unwrap();
+        assert!(vars.is_empty());
+        assert_eq!(rest, argv(&["--config", "frontend.yaml"]));
+
+        assert!(split_args(argv(&["--log-output"])).is_err());
+        assert!(split_args(argv(&["--log-level=loud"])).is_err());
+        assert_eq!(
+            split_args(argv(&["--log-levels"])).unwrap().1,
+            argv(&["--log-levels"])
+        );
+    }
+}
@@ -1,29 +1,132 @@
+mod backendreq;
+mod config;
+mod control;
 mod device;
 mod devicemodel;
+mod error;
 mod frontend;
 mod guest;
 mod interrupt;
+mod logger;
 mod mmio;
+#[cfg(test)]
+mod simulator;
+#[cfg(test)]
+mod testing;
 
+use std::process::ExitCode;
 use std::thread::Builder;
 
 use bao_sys::utils::parse_arguments;
+use config::DeviceOptionsTable;
+use control::{ControlServer, CONTROL_SOCKET_ENV};
 use frontend::BaoFrontend;
+use log::{error, info};
+
+/// Blocks the termination signals (SIGINT and SIGTERM) on the calling thread.
+/// The threads spawned afterwards inherit the signal mask, so the signals are
+/// only delivered through `wait_termination_signal`.
+///
+/// # Return
+///
+/// * `libc::sigset_t` - The set of blocked signals.
+fn block_termination_signals() -> libc::sigset_t {
+    // SAFETY: The signal set is initialized by sigemptyset before being used and
+    // the return values are checked.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        assert_eq!(ret, 0, "failed to block the termination signals");
+        set
+    }
+}
+
+/// Parks the calling thread until one of the given signals is received.
+///
+/// # Arguments
+///
+/// * `set` - The set of blocked signals to wait for.
+///
+/// # Return
+///
+/// * `i32` - The received signal.
+fn wait_termination_signal(set: &libc::sigset_t) -> i32 {
+    let mut signal = 0;
+    // SAFETY: Both pointers are valid for the duration of the call.
+    while unsafe { libc::sigwait(set, &mut signal) } != 0 {}
+    signal
+}
+
+fn main() -> ExitCode {
+    // Apply the logging options of the command line
+    if let Err(err) = logger::apply_args() {
+        eprintln!("Error: invalid log options: {}", err);
+        return ExitCode::FAILURE;
+    }
+
+    // Install the logger
+    if let Err(err) = logger::init() {
+        eprintln!("Error: invalid log configuration: {}", err);
+        return ExitCode::FAILURE;
+    }
 
-fn main() {
     // Print the starting message
-    println!("[Start] bao-vhost-frontend.");
+    info!("[Start] bao-vhost-frontend.");
+
+    // Block the termination signals before spawning any thread
+    let signals = block_termination_signals();
 
     // Parse the command line arguments
-    let config_frontends = parse_arguments().unwrap();
+    let config_frontends = match parse_arguments() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("invalid command line: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Load the per-device options
+    let device_options = match DeviceOptionsTable::from_env() {
+        Ok(table) => std::sync::Arc::new(table),
+        Err(err) => {
+            error!("invalid device options: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
     // Create a new BaoFrontend object
-    let frontend = BaoFrontend::new().unwrap();
+    let frontend = match BaoFrontend::new() {
+        Ok(frontend) => frontend,
+        Err(err) => {
+            error!("frontend creation failed: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Create the control socket, if requested
+    let mut control = match std::env::var(CONTROL_SOCKET_ENV) {
+        Ok(path) => match ControlServer::new(&path, frontend.clone()) {
+            Ok(server) => {
+                info!("control socket listening path={}", server.path());
+                Some(server)
+            }
+            Err(err) => {
+                error!("control socket creation failed path={}: {:?}", path, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(_) => None,
+    };
 
     // Iterate over frontends
     for config_frontend in config_frontends.frontends.into_iter() {
         // Clone the frontend
         let fe: std::sync::Arc<BaoFrontend> = frontend.clone();
+        // Clone the device options
+        let options = device_options.clone();
         // Create a new thread for each frontend
         frontend.push_thread(
             Builder::new()
@@ -37,6 +140,7 @@ fn main() {
                         // Iterate over devices within each guest
                         for config_device in config_guest.devices.iter() {
                             match fe.add_device(
+                                config_frontend.id as u16,
                                 config_guest.id as u16,
                                 config_device.id as u64,
                                 config_device.irq as u64,
@@ -45,16 +149,28 @@ fn main() {
                                 config_guest.ram_size,
                                 config_guest.shmem_path.clone(),
                                 config_guest.socket_path.clone(),
+                                options.get(config_guest.id as u16, config_device.addr),
                             ) {
                                 Ok(_) => {
-                                    println!(
-                                        "Device {} at 0x{:x} added.",
-                                        config_device.id, config_device.addr
+                                    info!(
+                                        "device added frontend={}/{} guest={} id={} addr=0x{:x}",
+                                        config_frontend.name,
+                                        config_frontend.id,
+                                        config_guest.id,
+                                        config_device.id,
+                                        config_device.addr
                                     );
                                 }
                                 Err(err) => {
-                                    println!("Error: {:?}", err);
-                                    fe.remove_device(config_guest.id as u16, config_device.addr);
+                                    error!(
+                                        "device addition failed frontend={}/{} guest={} id={} addr=0x{:x}: {:?}",
+                                        config_frontend.name,
+                                        config_frontend.id,
+                                        config_guest.id,
+                                        config_device.id,
+                                        config_device.addr,
+                                        err
+                                    );
                                 }
                             }
                         }
@@ -64,9 +180,26 @@ fn main() {
         );
     }
 
+    // Park the main thread until a termination signal is received
+    let signal = wait_termination_signal(&signals);
+    info!("received signal {}, shutting down..", signal);
+
+    // Stop accepting control requests
+    if let Some(server) = control.as_mut() {
+        server.exit();
+    }
+
+    // Tear down the guests and their devices
+    let ret = frontend.exit();
+
     // Print the ending message
-    println!("[End] bao-vhost-frontend.");
+    info!("[End] bao-vhost-frontend.");
 
-    // Loop forever
-    loop {}
+    match ret {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("shutdown failed: {:?}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
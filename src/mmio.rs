@@ -9,31 +9,132 @@
 //! - Device configuration space operations.
 //! - Device write and read operations.
 
-use super::{device::BaoDevice, guest::BaoGuest};
+use super::{
+    config::{transport_feature_names, DeviceOptions},
+    device::BaoDevice,
+    guest::BaoGuest,
+};
 use bao_sys::{defines::*, error::*, types::*};
 use libc::{MAP_SHARED, PROT_READ, PROT_WRITE};
+use log::{debug, error, info, trace, warn};
 use std::fs::OpenOptions;
 use std::os::fd::AsRawFd;
 use std::sync::Arc;
-use vhost::vhost_user::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
+use vhost::vhost_user::message::VHOST_USER_CONFIG_OFFSET;
+use vhost::VringConfigData;
 use vhost_user_frontend::{Generic, GuestMemoryMmap, GuestRegionMmap, VirtioDevice};
-use virtio_bindings::virtio_config::{VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1};
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FAILED, VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_CONFIG_S_NEEDS_RESET,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_PACKED, VIRTIO_F_RING_RESET, VIRTIO_F_VERSION_1,
+};
 use virtio_bindings::virtio_mmio::{
     VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
     VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
-    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INT_VRING,
-    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW,
-    VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY,
-    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
-    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
-    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION,
+    VIRTIO_MMIO_GUEST_PAGE_SIZE, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_ALIGN, VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+    VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW,
+    VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL,
+    VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_SHM_BASE_HIGH,
+    VIRTIO_MMIO_SHM_BASE_LOW, VIRTIO_MMIO_SHM_LEN_HIGH, VIRTIO_MMIO_SHM_LEN_LOW,
+    VIRTIO_MMIO_SHM_SEL, VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION,
 };
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{
-    guest_memory::FileOffset, ByteValued, GuestAddress, GuestMemoryAtomic, MmapRegion,
+    guest_memory::FileOffset, ByteValued, Bytes, GuestAddress, GuestAddressSpace,
+    GuestMemoryAtomic, MmapRegion,
 };
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
+/// QueueReset register of virtio-mmio 1.2 (not exported by the Linux headers).
+/// More info: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-1650002
+pub const VIRTIO_MMIO_QUEUE_RESET: u32 = 0x0c0;
+
+/// Default page size and used ring alignment of the legacy virtqueues, until the driver
+/// writes the GuestPageSize and QueueAlign registers.
+const LEGACY_PAGE_SIZE: u32 = 4096;
+
+/// Vring base of a packed virtqueue starting at the beginning of its ring, where both wrap
+/// counters are set.
+const PACKED_VRING_BASE: u32 = packed_vring_base(0, true, 0, true);
+
+/// Encodes the vring base of a packed virtqueue, made of the available (bits 0-14) and used
+/// (bits 16-30) indexes, each followed by its wrap counter (bits 15 and 31).
+///
+/// # Arguments
+///
+/// * `avail_idx` - Index of the next available descriptor.
+/// * `avail_wrap` - Available ring wrap counter.
+/// * `used_idx` - Index of the next used descriptor.
+/// * `used_wrap` - Used ring wrap counter.
+///
+/// # Returns
+///
+/// * `u32` - The vring base.
+const fn packed_vring_base(
+    avail_idx: u16,
+    avail_wrap: bool,
+    used_idx: u16,
+    used_wrap: bool,
+) -> u32 {
+    (avail_idx as u32 & 0x7fff)
+        | ((avail_wrap as u32) << 15)
+        | ((used_idx as u32 & 0x7fff) << 16)
+        | ((used_wrap as u32) << 31)
+}
+
+/// Returns the name of a virtio-mmio register, used to trace the accesses.
+///
+/// # Arguments
+///
+/// * `offset` - Offset of the register.
+///
+/// # Returns
+///
+/// * `&'static str` - Name of the register.
+pub fn register_name(offset: u64) -> &'static str {
+    if offset >= VHOST_USER_CONFIG_OFFSET as u64 {
+        return "CONFIG";
+    }
+
+    match offset as u32 {
+        VIRTIO_MMIO_MAGIC_VALUE => "MAGIC_VALUE",
+        VIRTIO_MMIO_VERSION => "VERSION",
+        VIRTIO_MMIO_DEVICE_ID => "DEVICE_ID",
+        VIRTIO_MMIO_VENDOR_ID => "VENDOR_ID",
+        VIRTIO_MMIO_DEVICE_FEATURES => "DEVICE_FEATURES",
+        VIRTIO_MMIO_DEVICE_FEATURES_SEL => "DEVICE_FEATURES_SEL",
+        VIRTIO_MMIO_DRIVER_FEATURES => "DRIVER_FEATURES",
+        VIRTIO_MMIO_DRIVER_FEATURES_SEL => "DRIVER_FEATURES_SEL",
+        VIRTIO_MMIO_QUEUE_SEL => "QUEUE_SEL",
+        VIRTIO_MMIO_QUEUE_NUM_MAX => "QUEUE_NUM_MAX",
+        VIRTIO_MMIO_QUEUE_NUM => "QUEUE_NUM",
+        VIRTIO_MMIO_QUEUE_READY => "QUEUE_READY",
+        VIRTIO_MMIO_QUEUE_NOTIFY => "QUEUE_NOTIFY",
+        VIRTIO_MMIO_INTERRUPT_STATUS => "INTERRUPT_STATUS",
+        VIRTIO_MMIO_INTERRUPT_ACK => "INTERRUPT_ACK",
+        VIRTIO_MMIO_STATUS => "STATUS",
+        VIRTIO_MMIO_QUEUE_DESC_LOW => "QUEUE_DESC_LOW",
+        VIRTIO_MMIO_QUEUE_DESC_HIGH => "QUEUE_DESC_HIGH",
+        VIRTIO_MMIO_QUEUE_AVAIL_LOW => "QUEUE_AVAIL_LOW",
+        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => "QUEUE_AVAIL_HIGH",
+        VIRTIO_MMIO_QUEUE_USED_LOW => "QUEUE_USED_LOW",
+        VIRTIO_MMIO_QUEUE_USED_HIGH => "QUEUE_USED_HIGH",
+        VIRTIO_MMIO_CONFIG_GENERATION => "CONFIG_GENERATION",
+        VIRTIO_MMIO_SHM_SEL => "SHM_SEL",
+        VIRTIO_MMIO_SHM_LEN_LOW => "SHM_LEN_LOW",
+        VIRTIO_MMIO_SHM_LEN_HIGH => "SHM_LEN_HIGH",
+        VIRTIO_MMIO_SHM_BASE_LOW => "SHM_BASE_LOW",
+        VIRTIO_MMIO_SHM_BASE_HIGH => "SHM_BASE_HIGH",
+        VIRTIO_MMIO_QUEUE_RESET => "QUEUE_RESET",
+        VIRTIO_MMIO_GUEST_PAGE_SIZE => "GUEST_PAGE_SIZE",
+        VIRTIO_MMIO_QUEUE_ALIGN => "QUEUE_ALIGN",
+        VIRTIO_MMIO_QUEUE_PFN => "QUEUE_PFN",
+        _ => "UNKNOWN",
+    }
+}
+
 /// Struct representing a Virtqueue.
 ///
 /// # Attributes
@@ -47,6 +148,9 @@ use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 /// * `avail_hi` - MMIO Queue Available Area High
 /// * `used_lo` - MMIO Queue Used Area Low
 /// * `used_hi` - MMIO Queue Used Area High
+/// * `align` - MMIO Queue Align (legacy)
+/// * `pfn` - MMIO Queue PFN (legacy)
+/// * `packed_base` - Vring base the backend stopped a packed virtqueue at, when disabled
 /// * `kick` - MMIO Queue Notify
 struct VirtQueue {
     ready: u32,
@@ -58,9 +162,68 @@ struct VirtQueue {
     avail_hi: u32,
     used_lo: u32,
     used_hi: u32,
+    align: u32,
+    pfn: u32,
+    packed_base: Option<u32>,
     kick: EventFd,
 }
 
+impl VirtQueue {
+    /// Constructor function for VirtQueue, with the registers at their initial values.
+    ///
+    /// # Arguments
+    ///
+    /// * `size_max` - Maximum size of the virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The virtqueue.
+    fn new(size_max: u32) -> Self {
+        Self {
+            ready: 0,
+            size: 0,
+            size_max,
+            desc_lo: 0,
+            desc_hi: 0,
+            avail_lo: 0,
+            avail_hi: 0,
+            used_lo: 0,
+            used_hi: 0,
+            align: LEGACY_PAGE_SIZE,
+            pfn: 0,
+            packed_base: None,
+            kick: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    /// Method to reset the virtqueue registers, discarding the pending kicks.
+    fn reset(&mut self) {
+        self.ready = 0;
+        self.size = 0;
+        self.desc_lo = 0;
+        self.desc_hi = 0;
+        self.avail_lo = 0;
+        self.avail_hi = 0;
+        self.used_lo = 0;
+        self.used_hi = 0;
+        self.align = LEGACY_PAGE_SIZE;
+        self.pfn = 0;
+        self.packed_base = None;
+        let _ = self.kick.read();
+    }
+}
+
+/// Reason for rejecting the features written by the driver.
+///
+/// # Variants
+///
+/// * `Violation` - The driver accepted features it may not accept.
+/// * `Unsupported` - The driver features cannot be negotiated.
+enum FeatureRejection {
+    Violation(String),
+    Unsupported(String),
+}
+
 /// Struct representing a Bao MMIO.
 ///
 /// # Attributes
@@ -74,12 +237,15 @@ struct VirtQueue {
 /// * `device_features_sel` - MMIO Device Features Select
 /// * `driver_features` - MMIO Driver Features
 /// * `driver_features_sel` - MMIO Driver Features Select
-/// * `interrupt_state` - MMIO Interrupt State
-/// * `queues_count` - MMIO Queues Count
-/// * `queues` - MMIO Queues
+/// * `guest_page_size` - MMIO Guest Page Size (legacy)
 /// * `vq` - MMIO Virtqueues
 /// * `regions` - Memory Regions
+/// * `mem` - Guest memory built from the regions, shared by every activation
+/// * `features_acked` - Whether the driver features were negotiated with the backend
+/// * `activated` - Whether the device was activated
 /// * `guest` - Associated BaoGuest object
+/// * `ioeventfds` - Whether the kick eventfds are registered with the guest
+/// * `target` - Log target of the device
 pub struct BaoMmio {
     addr: u64,
     magic: [u8; 4],
@@ -90,12 +256,15 @@ pub struct BaoMmio {
     device_features_sel: u32,
     driver_features: u64,
     driver_features_sel: u32,
-    interrupt_state: u32,
-    queues_count: usize,
-    queues: Vec<(usize, Queue, EventFd)>,
+    guest_page_size: u32,
     vq: Vec<VirtQueue>,
     regions: Vec<GuestRegionMmap>,
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    features_acked: bool,
+    activated: bool,
     guest: Arc<BaoGuest>,
+    ioeventfds: bool,
+    target: String,
 }
 
 impl BaoMmio {
@@ -109,6 +278,9 @@ impl BaoMmio {
     /// * `ram_addr` - Guest RAM address to configure the memory region.
     /// * `ram_size` - Guest RAM size to configure the memory region.
     /// * `shmem_path` - Path to the shared memory file.
+    /// * `target` - Log target of the device.
+    /// * `ioeventfd` - Whether to register the kick eventfds with the guest.
+    /// * `legacy` - Whether the device implements the legacy (version 1) virtio-mmio layout.
     ///
     /// # Returns
     ///
@@ -120,33 +292,85 @@ impl BaoMmio {
         ram_addr: u64,
         ram_size: u64,
         shmem_path: String,
+        target: String,
+        ioeventfd: bool,
+        legacy: bool,
     ) -> Result<Self> {
-        // Get the maximum queue sizes.
-        let sizes = gdev.queue_max_sizes();
+        // Create the BaoMmio device with the virtqueues.
+        let mut mmio = Self::with_queues(gdev.queue_max_sizes(), guest, addr, target, legacy);
+
+        // Register the kick eventfds, unless the device opted out of it.
+        if ioeventfd {
+            mmio.register_ioeventfds()?;
+        }
 
-        // Create the BaoMmio device.
-        let mut mmio = Self {
+        // Map the region.
+        // The mmap_offset is set to 0 because the base address of Bao's shared memory driver is
+        // already defined statically in the backend device tree.
+        match mmio.map_region(0, &shmem_path, ram_addr, ram_size as usize) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+
+        // Return the BaoMmio.
+        Ok(mmio)
+    }
+
+    /// Creates the BaoMmio registers and virtqueues, before any kick eventfd or memory region
+    /// is set up.
+    ///
+    /// # Arguments
+    ///
+    /// * `sizes` - Maximum sizes of the virtqueues.
+    /// * `guest` - BaoGuest object.
+    /// * `addr` - MMIO base address.
+    /// * `target` - Log target of the device.
+    /// * `legacy` - Whether the device implements the legacy (version 1) virtio-mmio layout.
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The BaoMmio.
+    fn with_queues(
+        sizes: &[u16],
+        guest: Arc<BaoGuest>,
+        addr: u64,
+        target: String,
+        legacy: bool,
+    ) -> Self {
+        Self {
             addr,
             magic: [b'v', b'i', b'r', b't'],
-            version: 2,
+            version: if legacy { 1 } else { 2 },
             vendor_id: 0x4d564b4c,
             status: 0,
             queue_sel: 0,
             device_features_sel: 0,
             driver_features: 0,
             driver_features_sel: 0,
-            interrupt_state: 0,
-            queues_count: sizes.len(),
-            queues: Vec::with_capacity(sizes.len()),
-            vq: Vec::new(),
+            guest_page_size: LEGACY_PAGE_SIZE,
+            vq: sizes
+                .iter()
+                .map(|size| VirtQueue::new(*size as u32))
+                .collect(),
             regions: Vec::new(),
-            guest: guest.clone(),
-        };
-
-        // Create the virtqueues.
-        for (index, size) in sizes.iter().enumerate() {
-            let kick = EventFd::new(EFD_NONBLOCK).unwrap();
+            mem: None,
+            features_acked: false,
+            activated: false,
+            guest,
+            ioeventfds: false,
+            target,
+        }
+    }
 
+    /// Method to register the kick eventfds with the guest, so that the kernel signals them on
+    /// QUEUE_NOTIFY writes. If the kernel module does not support ioeventfds, the QUEUE_NOTIFY
+    /// writes keep reaching the frontend, which signals the kick eventfds itself.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn register_ioeventfds(&mut self) -> Result<()> {
+        for (index, vq) in self.vq.iter().enumerate() {
             // Create a BaoIoEventFd struct.
             // With QEMU we only need one for all, because QEMU only sets one ioeventfd per memory listener.
             // However, with this approach (vhost-user), we need to create a new ioeventfd for each queue
@@ -154,45 +378,28 @@ impl BaoMmio {
             // pass the index of the virtqueue to the `data` field to match with the `value` field of the
             // `bao_io_request` struct inside the bao hypervisor service module.
             let ioeventfd = BaoIoEventFd {
-                fd: kick.as_raw_fd() as u32,
+                fd: vq.kick.as_raw_fd() as u32,
                 flags: BAO_IOEVENTFD_FLAG_DATAMATCH, // Allow a eventfd per Virtqueue
-                addr: addr + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+                addr: self.addr + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
                 len: 4,
                 reserved: 0,
                 data: index as u64, // Index of the Virtqueue to match with the 'value' field of the 'bao_io_request' struct
             };
 
             // Register the kick eventfd.
-            match guest.dm.lock().unwrap().create_ioeventfd(ioeventfd) {
-                Ok(_) => (),
+            match self.guest.dm.create_ioeventfd(ioeventfd) {
+                Ok(_) => self.ioeventfds = true,
+                Err(Error::BaoIoctlError(err, _))
+                    if index == 0 && err.raw_os_error() == Some(libc::ENOTTY) =>
+                {
+                    info!(target: &self.target, "ioeventfd unavailable, kicking from userspace");
+                    return Ok(());
+                }
                 Err(err) => return Err(err),
             }
-
-            // Create the virtqueue.
-            mmio.vq.push(VirtQueue {
-                ready: 0,
-                size: 0,
-                size_max: *size as u32,
-                desc_lo: 77,
-                desc_hi: 0,
-                avail_lo: 0,
-                avail_hi: 0,
-                used_lo: 0,
-                used_hi: 0,
-                kick,
-            });
-        }
-
-        // Map the region.
-        // The mmap_offset is set to 0 because the base address of Bao's shared memory driver is
-        // already defined statically in the backend device tree.
-        match mmio.map_region(0, &shmem_path, ram_addr, ram_size as usize) {
-            Ok(_) => (),
-            Err(err) => return Err(err),
         }
 
-        // Return the BaoMmio.
-        Ok(mmio)
+        Ok(())
     }
 
     /// Method to read from the device configuration space.
@@ -250,54 +457,80 @@ impl BaoMmio {
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     fn io_read(&self, req: &mut BaoIoRequest, dev: &BaoDevice, offset: u64) -> Result<()> {
-        // Get the virtqueue.
-        let vq = &self.vq[self.queue_sel as usize];
         // Get the generic device.
         let gdev = dev.gdev.lock().unwrap();
 
         // Read the data from the device by writing it to the request value.
         req.value = match offset as u32 {
-            VIRTIO_MMIO_MAGIC_VALUE => u32::from_le_bytes(self.magic),
-            VIRTIO_MMIO_VERSION => self.version as u32,
             VIRTIO_MMIO_DEVICE_ID => gdev.device_type(),
-            VIRTIO_MMIO_VENDOR_ID => self.vendor_id,
-            VIRTIO_MMIO_STATUS => self.status,
-            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_state | VIRTIO_MMIO_INT_VRING,
-            VIRTIO_MMIO_QUEUE_NUM_MAX => vq.size_max,
+            VIRTIO_MMIO_INTERRUPT_STATUS => dev.interrupt().status(),
             VIRTIO_MMIO_DEVICE_FEATURES => {
                 if self.device_features_sel > 1 {
                     return Err(Error::InvalidFeatureSel(self.device_features_sel));
                 }
 
-                let mut features = gdev.device_features();
-                features |= 1 << VIRTIO_F_VERSION_1;
-                features |= 1 << VIRTIO_F_IOMMU_PLATFORM;
-                (features >> (32 * self.device_features_sel)) as u32
-            }
-            VIRTIO_MMIO_QUEUE_READY => vq.ready,
-            VIRTIO_MMIO_QUEUE_DESC_LOW => vq.desc_lo,
-            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq.desc_hi,
-            VIRTIO_MMIO_QUEUE_USED_LOW => vq.used_lo,
-            VIRTIO_MMIO_QUEUE_USED_HIGH => vq.used_hi,
-            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq.avail_lo,
-            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq.avail_hi,
-            VIRTIO_MMIO_CONFIG_GENERATION => {
-                // TODO
-                // Reading from this register returns a value describing a version of the device-specific configuration space layout.
-                // The driver can then access the configuration space and, when finished, read ConfigGeneration again.
-                // If no part of the configuration space has changed between these two ConfigGeneration reads, the returned
-                // values are identical. If the values are different, the configuration space accesses were not atomic and the
-                // driver has to perform the operations again.
-                // More info: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-1650002
-                //            https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-220005
-                0
+                (self.device_features(dev, &gdev) >> (32 * self.device_features_sel)) as u32
             }
-            _ => return Err(Error::InvalidMmioAddr("read", offset)),
+            // Reading from this register returns a value describing a version of the device-specific configuration space layout.
+            // The driver can then access the configuration space and, when finished, read ConfigGeneration again.
+            // If no part of the configuration space has changed between these two ConfigGeneration reads, the returned
+            // values are identical. If the values are different, the configuration space accesses were not atomic and the
+            // driver has to perform the operations again.
+            // More info: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-1650002
+            //            https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-220005
+            VIRTIO_MMIO_CONFIG_GENERATION => dev.config_generation(),
+            reg => match self.transport_read(reg) {
+                Some(value) => value,
+                None => return Err(Error::InvalidMmioAddr("read", offset)),
+            },
         } as u64;
 
         Ok(())
     }
 
+    /// Method to read a register holding the transport state, which does not involve the
+    /// device.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - Offset of the register.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u32>` - The value of the register, or None if the register is not part of the
+    ///   transport state.
+    fn transport_read(&self, reg: u32) -> Option<u32> {
+        // Get the virtqueue, whose registers read as 0 if the driver selected a virtqueue that
+        // does not exist.
+        let vq = self.vq.get(self.queue_sel as usize);
+        let vq_reg = |reg: fn(&VirtQueue) -> u32| vq.map_or(0, reg);
+
+        Some(match reg {
+            VIRTIO_MMIO_MAGIC_VALUE => u32::from_le_bytes(self.magic),
+            VIRTIO_MMIO_VERSION => self.version as u32,
+            VIRTIO_MMIO_VENDOR_ID => self.vendor_id,
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_QUEUE_NUM_MAX => vq_reg(|vq| vq.size_max),
+            VIRTIO_MMIO_QUEUE_READY => vq_reg(|vq| vq.ready),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => vq_reg(|vq| vq.desc_lo),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq_reg(|vq| vq.desc_hi),
+            VIRTIO_MMIO_QUEUE_USED_LOW => vq_reg(|vq| vq.used_lo),
+            VIRTIO_MMIO_QUEUE_USED_HIGH => vq_reg(|vq| vq.used_hi),
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq_reg(|vq| vq.avail_lo),
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq_reg(|vq| vq.avail_hi),
+            // The device has no shared memory region, and reading the length of a region that
+            // does not exist returns -1.
+            VIRTIO_MMIO_SHM_LEN_LOW
+            | VIRTIO_MMIO_SHM_LEN_HIGH
+            | VIRTIO_MMIO_SHM_BASE_LOW
+            | VIRTIO_MMIO_SHM_BASE_HIGH => u32::MAX,
+            // The queue reset completes before the write returns.
+            VIRTIO_MMIO_QUEUE_RESET => 0,
+            VIRTIO_MMIO_QUEUE_PFN if self.legacy() => vq_reg(|vq| vq.pfn),
+            _ => return None,
+        })
+    }
+
     /// Method to perform an I/O write operation.
     ///
     /// # Arguments
@@ -310,68 +543,96 @@ impl BaoMmio {
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     fn io_write(&mut self, req: &mut BaoIoRequest, dev: &BaoDevice, offset: u64) -> Result<()> {
-        // Get the virtqueue.
-        let vq = &mut self.vq[self.queue_sel as usize];
+        let legacy = self.legacy();
 
         // Write the data to the device.
         match offset as u32 {
             VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = req.value as u32,
             VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = req.value as u32,
             VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = req.value as u32,
-            VIRTIO_MMIO_STATUS => self.status = req.value as u32,
-            VIRTIO_MMIO_QUEUE_NUM => vq.size = req.value as u32,
-            VIRTIO_MMIO_QUEUE_DESC_LOW => vq.desc_lo = req.value as u32,
-            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq.desc_hi = req.value as u32,
-            VIRTIO_MMIO_QUEUE_USED_LOW => vq.used_lo = req.value as u32,
-            VIRTIO_MMIO_QUEUE_USED_HIGH => vq.used_hi = req.value as u32,
-            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq.avail_lo = req.value as u32,
-            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq.avail_hi = req.value as u32,
+            // Every selected shared memory region reads as missing.
+            VIRTIO_MMIO_SHM_SEL => {}
+            VIRTIO_MMIO_STATUS => {
+                // Writing 0 resets the device.
+                if req.value == 0 {
+                    return self.reset(dev);
+                }
+
+                self.set_status(req.value as u32, dev);
+            }
+            VIRTIO_MMIO_QUEUE_NUM => self.selected_vq()?.size = req.value as u32,
+            VIRTIO_MMIO_QUEUE_DESC_LOW => self.selected_vq()?.desc_lo = req.value as u32,
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => self.selected_vq()?.desc_hi = req.value as u32,
+            VIRTIO_MMIO_QUEUE_USED_LOW => self.selected_vq()?.used_lo = req.value as u32,
+            VIRTIO_MMIO_QUEUE_USED_HIGH => self.selected_vq()?.used_hi = req.value as u32,
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.selected_vq()?.avail_lo = req.value as u32,
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.selected_vq()?.avail_hi = req.value as u32,
             VIRTIO_MMIO_INTERRUPT_ACK => {
-                self.interrupt_state &= !(req.value as u32);
+                dev.interrupt().ack(req.value as u32);
             }
             VIRTIO_MMIO_DRIVER_FEATURES => {
-                self.driver_features |=
-                    ((req.value as u32) as u64) << (32 * self.driver_features_sel);
-
-                if self.driver_features_sel == 1 {
-                    if (self.driver_features & (1 << VIRTIO_F_VERSION_1)) == 0 {
-                        return Err(Error::MmioLegacyNotSupported);
-                    }
-                    if (self.driver_features & (1 << VIRTIO_F_IOMMU_PLATFORM)) == 0 {
-                        return Err(Error::IommuPlatformNotSupported);
-                    }
+                // The features are written after setting DRIVER and before setting FEATURES_OK
+                // (DRIVER_OK for legacy devices, which have no FEATURES_OK).
+                let accepted = if legacy {
+                    VIRTIO_CONFIG_S_DRIVER_OK
                 } else {
-                    // Guest sends feature sel 1 first, followed by 0. Once that is done, lets
-                    // negotiate the vhost-user protocol features.
-                    // Note: For now, we don't assume any vhost-user protocol features. We just negotiate the
-                    // features that the virtio driver supports. However, by default, the vhost-user frontend
-                    // enables: 1) Multiple queues, 2) VirtIO device configuration, 3) Sending reply messages
-                    // for requests.
-                    dev.gdev
-                        .lock()
-                        .unwrap()
-                        .negotiate_features(
-                            self.driver_features,
-                            VhostUserProtocolFeatures::empty(),
-                        )
-                        .map_err(Error::VhostFrontendError)?;
+                    VIRTIO_CONFIG_S_FEATURES_OK
+                };
+                if self.status & (VIRTIO_CONFIG_S_DRIVER | accepted) != VIRTIO_CONFIG_S_DRIVER {
+                    self.violation(&format!(
+                        "driver features bank {} written with 0x{:x}",
+                        self.driver_features_sel, req.value
+                    ));
+                    return Ok(());
+                }
+                if self.driver_features_sel > 1 {
+                    return Err(Error::InvalidFeatureSel(self.driver_features_sel));
                 }
+
+                // Replace the selected bank, which the driver may write more than once.
+                let shift = 32 * self.driver_features_sel;
+                self.driver_features = (self.driver_features & !(0xffff_ffff << shift))
+                    | (((req.value as u32) as u64) << shift);
             }
             VIRTIO_MMIO_QUEUE_READY => {
+                self.selected_vq()?;
                 if req.value == 1 {
                     // Initialize the virtqueue.
-                    self.init_vq()?;
-
-                    // Wait for all virtqueues to get initialized.
-                    if self.queues.len() == self.queues_count {
-                        self.activate_device(dev)?;
-                    }
+                    self.init_vq(dev)?;
                 } else {
-                    self.destroy_vq();
+                    self.destroy_vq(dev)?;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_RESET => {
+                self.selected_vq()?;
+                if req.value == 1 {
+                    self.reset_vq(dev)?;
+                }
+            }
+            VIRTIO_MMIO_GUEST_PAGE_SIZE if legacy => self.guest_page_size = req.value as u32,
+            VIRTIO_MMIO_QUEUE_ALIGN if legacy => self.selected_vq()?.align = req.value as u32,
+            VIRTIO_MMIO_QUEUE_PFN if legacy => {
+                self.selected_vq()?;
+                if req.value == 0 {
+                    self.destroy_vq(dev)?;
+                    self.selected_vq()?.pfn = 0;
+                } else if self.layout_legacy_vq(req.value as u32) {
+                    // Initialize the virtqueue.
+                    self.init_vq(dev)?;
                 }
             }
             VIRTIO_MMIO_QUEUE_NOTIFY => {
-                // This is handled in the Linux kernel now. Nothing to do here.
+                // This is handled in the Linux kernel when the kick eventfds are registered.
+                // Otherwise, the value written is the index of the virtqueue to be kicked.
+                if !self.ioeventfds {
+                    let vq = self
+                        .vq
+                        .get(req.value as usize)
+                        .ok_or(Error::InvalidMmioAddr("queue notify", req.value))?;
+                    vq.kick
+                        .write(1)
+                        .map_err(|err| Error::OpenFdFailed("kick", err))?;
+                }
             }
 
             _ => return Err(Error::InvalidMmioAddr("write", offset)),
@@ -380,6 +641,329 @@ impl BaoMmio {
         Ok(())
     }
 
+    /// Method to get the selected virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<&mut VirtQueue>` - The virtqueue, or an Error if the driver selected a virtqueue
+    ///   that does not exist.
+    fn selected_vq(&mut self) -> Result<&mut VirtQueue> {
+        let queue_sel = self.queue_sel;
+        self.vq
+            .get_mut(queue_sel as usize)
+            .ok_or(Error::InvalidMmioAddr("queue select", queue_sel as u64))
+    }
+
+    /// Method to check whether the device implements the legacy virtio-mmio layout.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True for a legacy (version 1) device.
+    fn legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// Method to get the features offered to the driver, applying the feature override and
+    /// leaving out the features masked by the device options and transport policy.
+    /// Legacy devices offer no transport feature above bit 31, as those require VERSION_1.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    /// * `gdev` - The generic vhost-user frontend object associated with the device.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The device features.
+    fn device_features(&self, dev: &BaoDevice, gdev: &Generic) -> u64 {
+        let features = if self.legacy() {
+            gdev.device_features() & 0xffff_ffff
+        } else {
+            gdev.device_features() | (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM)
+        };
+
+        // The backend implements the ring layout, so packed virtqueues are only offered if it
+        // offers them, whatever the override.
+        let overridden =
+            dev.options.features_override & (gdev.device_features() | !(1 << VIRTIO_F_RING_PACKED));
+
+        (features | overridden)
+            & !(dev.options.features_mask | dev.options.transport_features.masked)
+    }
+
+    /// Method to check whether the virtqueues use the packed layout, in which case the
+    /// descriptor, driver and device area registers hold the addresses of the descriptor ring
+    /// and of the driver and device event suppression structures.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if VIRTIO_F_RING_PACKED was negotiated.
+    fn packed(&self) -> bool {
+        self.features_acked && (self.driver_features & (1 << VIRTIO_F_RING_PACKED)) != 0
+    }
+
+    /// Method to get the features negotiated with the backend, which are limited to the ones
+    /// the backend offered, so they never include the masked or overridden features.
+    /// Bao guests are little-endian, so the rings of a legacy driver are laid out as the
+    /// VERSION_1 ones, and the backend is told so.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    /// * `gdev` - The generic vhost-user frontend object associated with the device.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The features negotiated with the backend.
+    fn backend_features(&self, dev: &BaoDevice, gdev: &Generic) -> u64 {
+        let features = if self.legacy() {
+            self.driver_features | (1 << VIRTIO_F_VERSION_1)
+        } else {
+            self.driver_features
+        };
+
+        features & gdev.device_features() & !dev.options.features_mask
+    }
+
+    /// Method to handle a status write other than a reset, enforcing the device status
+    /// handshake: the driver only sets bits, each initialization step requires the previous one,
+    /// the features are validated on FEATURES_OK and the device is activated on DRIVER_OK.
+    /// Legacy devices have no FEATURES_OK, so their features are validated on DRIVER_OK.
+    /// Status writes breaking the handshake are logged and ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The status written by the driver.
+    /// * `dev` - BaoDevice object.
+    fn set_status(&mut self, status: u32, dev: &BaoDevice) {
+        // DEVICE_NEEDS_RESET is owned by the device.
+        let mut status = status & !VIRTIO_CONFIG_S_NEEDS_RESET;
+        let old = self.status & !VIRTIO_CONFIG_S_NEEDS_RESET;
+
+        if let Err(what) = self.check_status(status) {
+            self.violation(&what);
+            return;
+        }
+
+        let set = status & !old;
+        if set & VIRTIO_CONFIG_S_FAILED != 0 {
+            warn!(target: &self.target, "driver gave up on the device status=0x{:x}", status);
+        }
+
+        // The driver reads FEATURES_OK back to know whether the features were accepted.
+        if set & VIRTIO_CONFIG_S_FEATURES_OK != 0 && !self.accept_features(dev) {
+            status &= !(VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK);
+        }
+
+        // A legacy driver is done with the features once it sets DRIVER_OK.
+        if self.legacy() && set & VIRTIO_CONFIG_S_DRIVER_OK != 0 && !self.accept_features(dev) {
+            status &= !VIRTIO_CONFIG_S_DRIVER_OK;
+        }
+
+        // Activate the device with the virtqueues made ready so far once the driver is ready.
+        if status & VIRTIO_CONFIG_S_DRIVER_OK != 0 && !self.activated {
+            if let Err(err) = self.activate_device(dev) {
+                self.fail(dev, err);
+            }
+        }
+
+        self.status = status | (self.status & VIRTIO_CONFIG_S_NEEDS_RESET);
+    }
+
+    /// Method to check that a status write follows the device status handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The status written by the driver, without DEVICE_NEEDS_RESET.
+    ///
+    /// # Returns
+    ///
+    /// * `std::result::Result<(), String>` - Ok(()) if the handshake is followed, or the
+    ///   description of the violation.
+    fn check_status(&self, status: u32) -> std::result::Result<(), String> {
+        let old = self.status & !VIRTIO_CONFIG_S_NEEDS_RESET;
+
+        // Bits are only cleared by a device reset.
+        if status & old != old {
+            return Err(format!("status 0x{:x} clears bits of 0x{:x}", status, old));
+        }
+
+        // Legacy devices have no FEATURES_OK.
+        if self.legacy() && status & VIRTIO_CONFIG_S_FEATURES_OK != 0 {
+            return Err(format!(
+                "status 0x{:x} sets FEATURES_OK on a legacy device",
+                status
+            ));
+        }
+
+        // Each initialization step requires the previous one.
+        let features_done = if self.legacy() {
+            VIRTIO_CONFIG_S_DRIVER
+        } else {
+            VIRTIO_CONFIG_S_FEATURES_OK
+        };
+        for (bit, required) in [
+            (VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_ACKNOWLEDGE),
+            (VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_CONFIG_S_DRIVER),
+            (VIRTIO_CONFIG_S_DRIVER_OK, features_done),
+        ] {
+            if status & bit != 0 && status & required == 0 {
+                return Err(format!(
+                    "status 0x{:x} sets 0x{:x} without 0x{:x}",
+                    status, bit, required
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Method to validate the driver features and negotiate them with the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the features were accepted.
+    fn accept_features(&mut self, dev: &BaoDevice) -> bool {
+        let (offered, implemented) = {
+            let gdev = dev.gdev.lock().unwrap();
+            (
+                self.device_features(dev, &gdev),
+                gdev.device_features() | (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM),
+            )
+        };
+
+        match self.check_features(offered, implemented, &dev.options) {
+            Ok(()) => {}
+            Err(FeatureRejection::Violation(what)) => {
+                self.violation(&what);
+                return false;
+            }
+            Err(FeatureRejection::Unsupported(why)) => {
+                warn!(
+                    target: &self.target,
+                    "driver features rejected features=0x{:x}: {}",
+                    self.driver_features,
+                    why
+                );
+                return false;
+            }
+        }
+
+        // Negotiate the features with the backend.
+        let mut gdev = dev.gdev.lock().unwrap();
+        let features = self.backend_features(dev, &gdev);
+        match dev.negotiate_features(&mut gdev, features) {
+            Ok(()) => {
+                self.features_acked = true;
+                true
+            }
+            Err(err) => {
+                self.fail(dev, err);
+                false
+            }
+        }
+    }
+
+    /// Method to validate the driver features against the offered ones and the device options.
+    ///
+    /// # Arguments
+    ///
+    /// * `offered` - The features offered to the driver.
+    /// * `implemented` - The features implemented by the backend and the frontend.
+    /// * `options` - The options of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `std::result::Result<(), FeatureRejection>` - Ok(()) if the features can be negotiated,
+    ///   or the reason for rejecting them.
+    fn check_features(
+        &self,
+        offered: u64,
+        implemented: u64,
+        options: &DeviceOptions,
+    ) -> std::result::Result<(), FeatureRejection> {
+        // The masked features are never negotiated.
+        let masked = self.driver_features & options.features_mask;
+        if masked != 0 {
+            return Err(FeatureRejection::Violation(format!(
+                "driver features 0x{:x} are masked by the device options",
+                masked
+            )));
+        }
+
+        // The driver may only accept offered features.
+        let unoffered = self.driver_features & !offered;
+        if unoffered != 0 {
+            return Err(FeatureRejection::Violation(format!(
+                "driver features 0x{:x} were not offered (offered 0x{:x})",
+                unoffered, offered
+            )));
+        }
+
+        // The overridden features the backend does not offer can only be declined, as nothing
+        // implements them: the frontend only implements VIRTIO_F_VERSION_1 and
+        // VIRTIO_F_IOMMU_PLATFORM.
+        let unimplemented = self.driver_features & options.features_override & !implemented;
+        if unimplemented != 0 {
+            return Err(FeatureRejection::Unsupported(format!(
+                "overridden features 0x{:x} are not offered by the backend",
+                unimplemented
+            )));
+        }
+
+        // Legacy drivers are only supported by legacy devices.
+        if !self.legacy() && (self.driver_features & (1 << VIRTIO_F_VERSION_1)) == 0 {
+            return Err(FeatureRejection::Unsupported(format!(
+                "{:?}",
+                Error::MmioLegacyNotSupported
+            )));
+        }
+
+        // The driver must accept the mandatory features it was offered.
+        let declined = offered & options.transport_features.forced & !self.driver_features;
+        if declined != 0 {
+            return Err(FeatureRejection::Unsupported(format!(
+                "driver declined mandatory features {} (see the transport_features option)",
+                transport_feature_names(declined)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Method to log a protocol violation of the driver, whose access is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - Description of the violation.
+    fn violation(&self, what: &str) {
+        warn!(
+            target: &self.target,
+            "driver protocol violation: {} status=0x{:x} driver_features=0x{:x} queue_sel={}",
+            what,
+            self.status,
+            self.driver_features,
+            self.queue_sel
+        );
+    }
+
+    /// Method to move the device to DEVICE_NEEDS_RESET after an internal failure, notifying
+    /// the driver with a configuration change interrupt.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    /// * `err` - The Error raised by the failure.
+    fn fail(&mut self, dev: &BaoDevice, err: Error) {
+        error!(target: &self.target, "device failure, needs reset: {:?}", err);
+        self.set_needs_reset();
+        dev.signal_config();
+    }
+
     /// Method to map a region.
     ///
     /// # Arguments
@@ -436,22 +1020,74 @@ impl BaoMmio {
         Ok(())
     }
 
-    /// Method to initialize the virtqueues.
+    /// Method to lay the selected legacy virtqueue out from the page frame number written by the
+    /// driver. The descriptor table starts at the page, followed by the available ring, while
+    /// the used ring starts at the next QueueAlign boundary.
+    ///
+    /// # Arguments
+    ///
+    /// * `pfn` - Guest page frame number of the virtqueue.
     ///
     /// # Returns
     ///
-    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    fn init_vq(&mut self) -> Result<()> {
-        let vq = &mut self.vq[self.queue_sel as usize];
-        let kick = vq.kick.try_clone().unwrap();
-        let vq_size = vq.size;
+    /// * `bool` - True if the virtqueue was laid out.
+    fn layout_legacy_vq(&mut self, pfn: u32) -> bool {
+        let vq = match self.vq.get(self.queue_sel as usize) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        if vq.ready == 1
+            || vq.size == 0
+            || !self.guest_page_size.is_power_of_two()
+            || !vq.align.is_power_of_two()
+        {
+            self.violation(&format!(
+                "queue pfn 0x{:x} written with page size 0x{:x}, align 0x{:x} and size {}",
+                pfn, self.guest_page_size, vq.align, vq.size
+            ));
+            return false;
+        }
+
+        // Compute the split ring addresses.
+        let size = vq.size as u64;
+        let align = vq.align as u64;
+        let desc = pfn as u64 * self.guest_page_size as u64;
+        let avail = desc + 16 * size;
+        // The available ring holds the flags, index, ring and used event fields.
+        let used = (avail + 6 + 2 * size + align - 1) & !(align - 1);
+
+        let vq = match self.vq.get_mut(self.queue_sel as usize) {
+            Some(vq) => vq,
+            None => return false,
+        };
+        vq.desc_lo = desc as u32;
+        vq.desc_hi = (desc >> 32) as u32;
+        vq.avail_lo = avail as u32;
+        vq.avail_hi = (avail >> 32) as u32;
+        vq.used_lo = used as u32;
+        vq.used_hi = (used >> 32) as u32;
+        vq.pfn = pfn;
+        true
+    }
+
+    /// Method to build the queue of a virtqueue from its registers.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `Queue` - The queue, starting at the beginning of the rings.
+    fn build_queue(&self, index: usize) -> Queue {
+        let vq = &self.vq[index];
 
         // Get the virtqueue addresses.
-        let desc = (((vq.desc_hi as u64) << 32) | vq.desc_lo as u64) as u64;
-        let avail = (((vq.avail_hi as u64) << 32) | vq.avail_lo as u64) as u64;
-        let used = (((vq.used_hi as u64) << 32) | vq.used_lo as u64) as u64;
+        let desc = ((vq.desc_hi as u64) << 32) | vq.desc_lo as u64;
+        let avail = ((vq.avail_hi as u64) << 32) | vq.avail_lo as u64;
+        let used = ((vq.used_hi as u64) << 32) | vq.used_lo as u64;
 
-        let mut queue = Queue::new(vq_size as u16).unwrap();
+        let mut queue = Queue::new(vq.size as u16).unwrap();
         queue.set_desc_table_address(Some((desc & 0xFFFFFFFF) as u32), Some((desc >> 32) as u32));
         queue.set_avail_ring_address(
             Some((avail & 0xFFFFFFFF) as u32),
@@ -460,16 +1096,173 @@ impl BaoMmio {
         queue.set_used_ring_address(Some((used & 0xFFFFFFFF) as u32), Some((used >> 32) as u32));
         queue.set_next_avail(0);
 
-        vq.ready = 1;
+        queue
+    }
 
-        self.queues.push((self.queue_sel as usize, queue, kick));
+    /// Method to describe the vring of a virtqueue from its registers, with guest addresses.
+    /// The driver and device areas of a packed virtqueue go in place of the available and used
+    /// rings.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `VringConfigData` - The vring description.
+    fn vring_config(&self, index: usize) -> VringConfigData {
+        let vq = &self.vq[index];
+
+        VringConfigData {
+            queue_max_size: vq.size_max as u16,
+            queue_size: vq.size as u16,
+            flags: 0,
+            desc_table_addr: ((vq.desc_hi as u64) << 32) | vq.desc_lo as u64,
+            used_ring_addr: ((vq.used_hi as u64) << 32) | vq.used_lo as u64,
+            avail_ring_addr: ((vq.avail_hi as u64) << 32) | vq.avail_lo as u64,
+            log_addr: None,
+        }
+    }
+
+    /// Method to read the used index of a split virtqueue, which is where the driver stands
+    /// and where a freshly laid out virtqueue starts.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - Guest memory.
+    /// * `index` - Index of the virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u16>` - A Result containing the used index, or an Error on failure.
+    fn used_idx(&self, mem: &GuestMemoryAtomic<GuestMemoryMmap>, index: usize) -> Result<u16> {
+        let vq = &self.vq[index];
+        let used = ((vq.used_hi as u64) << 32) | vq.used_lo as u64;
+
+        mem.memory()
+            .read_obj(GuestAddress(used + 2))
+            .map_err(|_| Error::MmapGuestMemoryFailed)
+    }
+
+    /// Method to build the queue of a virtqueue resuming where the driver stands, as given by
+    /// the used ring, which is also where a freshly laid out virtqueue starts.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - Guest memory.
+    /// * `index` - Index of the virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Queue>` - A Result containing the queue, or an Error on failure.
+    fn resume_queue(
+        &self,
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        index: usize,
+    ) -> Result<Queue> {
+        let mut queue = self.build_queue(index);
+
+        // Resume both rings at the used index.
+        let idx = self.used_idx(mem, index)?;
+        queue.set_next_avail(idx);
+        queue.set_next_used(idx);
+
+        Ok(queue)
+    }
+
+    /// Method to initialize the selected virtqueue.
+    /// Once the device is activated, the virtqueue is also enabled on the backend (late enable),
+    /// resuming where the driver stands for a split virtqueue. A packed one resumes where the
+    /// backend stopped it when it was disabled, or starts at the beginning of the ring the
+    /// driver has just laid out.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn init_vq(&mut self, dev: &BaoDevice) -> Result<()> {
+        let index = self.queue_sel as usize;
+
+        // Check if the virtqueue exists and is not already ready.
+        match self.vq.get_mut(index) {
+            Some(vq) if std::mem::replace(&mut vq.ready, 1) == 0 => {}
+            _ => return Ok(()),
+        }
+
+        if self.activated {
+            let mem = self.mem();
+            let base = if self.packed() {
+                self.vq[index].packed_base.unwrap_or(PACKED_VRING_BASE)
+            } else {
+                self.used_idx(&mem, index)? as u32
+            };
+            let vring = self.vring_config(index);
+            dev.enable_vring(&mem.memory(), index, &vring, base, &self.vq[index].kick)?;
+        }
 
         Ok(())
     }
 
-    /// Method to destroy the virtqueues.
-    fn destroy_vq(&mut self) {
-        self.queues.drain(..);
+    /// Method to destroy the selected virtqueue.
+    /// Once the device is activated, the virtqueue is also disabled on the backend. Packed rings
+    /// have no used index to resume from, so the vring base of a packed virtqueue is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn destroy_vq(&mut self, dev: &BaoDevice) -> Result<()> {
+        let index = self.queue_sel as usize;
+
+        // Check if the virtqueue exists and is ready.
+        match self.vq.get_mut(index) {
+            Some(vq) if std::mem::take(&mut vq.ready) == 1 => {}
+            _ => return Ok(()),
+        }
+
+        if self.activated {
+            let base = dev.disable_vring(index)?;
+            if self.packed() {
+                self.vq[index].packed_base = Some(base);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Method to reset the selected virtqueue, if VIRTIO_F_RING_RESET was negotiated.
+    /// The vring is stopped on the backend, and the virtqueue registers go back to their
+    /// initial values, so the driver can lay the virtqueue out again and make it ready, which
+    /// restarts the vring.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn reset_vq(&mut self, dev: &BaoDevice) -> Result<()> {
+        if !self.features_acked || (self.driver_features & (1 << VIRTIO_F_RING_RESET)) == 0 {
+            self.violation("queue reset without VIRTIO_F_RING_RESET");
+            return Ok(());
+        }
+
+        // Stop the vring.
+        self.destroy_vq(dev)?;
+
+        if let Some(vq) = self.vq.get_mut(self.queue_sel as usize) {
+            vq.reset();
+        }
+        debug!(target: &self.target, "queue {} reset", self.queue_sel);
+
+        Ok(())
     }
 
     /// Method to get the memory of the device.
@@ -478,12 +1271,21 @@ impl BaoMmio {
     ///
     /// * `GuestMemoryAtomic<GuestMemoryMmap>` - Guest memory mmap.
     fn mem(&mut self) -> GuestMemoryAtomic<GuestMemoryMmap> {
-        GuestMemoryAtomic::new(
-            GuestMemoryMmap::from_regions(self.regions.drain(..).collect()).unwrap(),
-        )
+        // Build the memory once, as the regions are handed over to it
+        let regions = &mut self.regions;
+        self.mem
+            .get_or_insert_with(|| {
+                GuestMemoryAtomic::new(
+                    GuestMemoryMmap::from_regions(regions.drain(..).collect()).unwrap(),
+                )
+            })
+            .clone()
     }
 
-    /// Method to activate the device.
+    /// Method to activate the device with the virtqueues made ready by the driver.
+    /// Drivers may leave virtqueues unused, or make them ready later on.
+    /// The Generic device only sets split vrings up, so packed ones are enabled on the backend
+    /// once the device is activated.
     ///
     /// # Arguments
     ///
@@ -493,13 +1295,208 @@ impl BaoMmio {
     ///
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     fn activate_device(&mut self, dev: &BaoDevice) -> Result<()> {
-        dev.gdev
-            .lock()
-            .unwrap()
-            .activate(self.mem(), dev.interrupt(), self.queues.drain(..).collect())
+        let ready: Vec<usize> = (0..self.vq.len())
+            .filter(|index| self.vq[*index].ready == 1)
+            .collect();
+        let packed = self.packed();
+        info!(
+            target: &self.target,
+            "activating device queues={} packed={}",
+            ready.len(),
+            packed
+        );
+
+        let queues: Vec<(usize, Queue, EventFd)> = if packed {
+            Vec::new()
+        } else {
+            ready
+                .iter()
+                .map(|index| {
+                    let kick = self.vq[*index].kick.try_clone().unwrap();
+                    (*index, self.build_queue(*index), kick)
+                })
+                .collect()
+        };
+
+        let mem = self.mem();
+        {
+            let mut gdev = dev.gdev.lock().unwrap();
+            dev.set_inflight(&mut gdev)?;
+            gdev.activate(mem.clone(), dev.interrupt(), queues)
+                .map_err(Error::VhostFrontendActivateError)?;
+        }
+        self.activated = true;
+
+        // Set the packed vrings up, starting at the beginning of their rings.
+        if packed {
+            for index in ready {
+                let vring = self.vring_config(index);
+                dev.enable_vring(
+                    &mem.memory(),
+                    index,
+                    &vring,
+                    PACKED_VRING_BASE,
+                    &self.vq[index].kick,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Method to replay the device state on a Generic vhost-user device connected to a
+    /// restarted backend.
+    /// Activating the device sends the memory table and, for each ready virtqueue, its
+    /// addresses, base and the existing kick and call eventfds. As the previous backend
+    /// cannot be asked for the vring bases, they are recovered from the used rings, while the
+    /// requests in flight are recovered by the backend from the inflight region, if any.
+    /// Packed rings have no used index to recover the bases from, so a device with packed
+    /// virtqueues moves to DEVICE_NEEDS_RESET instead, and is activated again by the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `gdev` - The generic vhost-user frontend object connected to the restarted backend.
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    pub fn replay(&mut self, gdev: &mut Generic, dev: &BaoDevice) -> Result<()> {
+        // Replay the negotiated features.
+        if self.features_acked {
+            let features = self.backend_features(dev, gdev);
+            dev.negotiate_features(gdev, features)?;
+        }
+
+        // Nothing else to replay until the device is activated.
+        if !self.activated {
+            return Ok(());
+        }
+
+        if self.packed() {
+            warn!(target: &self.target, "packed virtqueues cannot be resumed, device needs reset");
+            self.activated = false;
+            self.set_needs_reset();
+            dev.signal_config();
+            return Ok(());
+        }
+
+        let mem = self.mem();
+        let mut queues = Vec::new();
+        for (index, vq) in self.vq.iter().enumerate().filter(|(_, vq)| vq.ready == 1) {
+            // Every buffer made available before the crash was either used or is lost, so
+            // resume both rings at the used index.
+            let queue = self.resume_queue(&mem, index)?;
+            queues.push((index, queue, vq.kick.try_clone().unwrap()));
+        }
+
+        // Hand the inflight region back, so the backend resubmits the requests it did not complete.
+        dev.set_inflight(gdev)?;
+        gdev.activate(mem, dev.interrupt(), queues)
             .map_err(Error::VhostFrontendActivateError)
     }
 
+    /// Method to reset the device, as requested by the driver writing 0 to the status register.
+    /// The backend stops the vrings and drops the requests in flight, while the driver state
+    /// (features, virtqueues and pending interrupts) goes back to its initial values, so the
+    /// device can be initialized and activated again. The kick eventfds and the guest memory
+    /// are kept, as they stay registered and map the same regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - BaoDevice object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn reset(&mut self, dev: &BaoDevice) -> Result<()> {
+        // Reset the backend, if it was told anything.
+        let ret = if self.features_acked || self.activated {
+            let mut gdev = dev.gdev.lock().unwrap();
+            if self.activated {
+                gdev.reset();
+            }
+            dev.reset_backend(&mut gdev)
+        } else {
+            Ok(())
+        };
+
+        // Go back to the initial driver state.
+        self.status = 0;
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.features_acked = false;
+        self.activated = false;
+        for vq in self.vq.iter_mut() {
+            vq.reset();
+        }
+
+        // Drop the pending interrupts, as the configuration space may have changed.
+        dev.interrupt().ack(u32::MAX);
+        dev.bump_config_generation();
+
+        debug!(target: &self.target, "device reset");
+        ret
+    }
+
+    /// Device status getter.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The device status register.
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Method to move the device to DEVICE_NEEDS_RESET.
+    pub fn set_needs_reset(&mut self) {
+        self.status |= VIRTIO_CONFIG_S_NEEDS_RESET;
+    }
+
+    /// Driver features getter.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The features written by the driver.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// Method to exit the BaoMmio by unregistering the kick eventfds.
+    /// This is idempotent, so it is safe to call it before the BaoMmio is dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or the first Error raised on failure.
+    pub fn exit(&mut self) -> Result<()> {
+        // Check if the kick eventfds were already unregistered
+        if !std::mem::take(&mut self.ioeventfds) {
+            return Ok(());
+        }
+
+        let mut ret = Ok(());
+        for (index, vq) in self.vq.iter().enumerate() {
+            // Create a BaoIoEventFd struct
+            let ioeventfd = BaoIoEventFd {
+                fd: vq.kick.as_raw_fd() as u32,
+                flags: BAO_IOEVENTFD_FLAG_DEASSIGN, // Deassign the eventfd
+                addr: self.addr + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+                len: 4,
+                reserved: 0,
+                data: index as u64, // Index of the Virtqueue to match with the 'value' field of the 'bao_io_request' struct
+            };
+
+            // Unregister the kick eventfd, carrying on with the remaining ones on failure.
+            if let Err(err) = self.guest.dm.create_ioeventfd(ioeventfd) {
+                ret = ret.and(Err(err));
+            }
+        }
+
+        ret
+    }
+
     /// Method to handle an I/O event.
     ///
     /// # Arguments
@@ -512,13 +1509,18 @@ impl BaoMmio {
     /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
     pub fn io_event(&mut self, req: &mut BaoIoRequest, dev: &BaoDevice) -> Result<()> {
         let mut offset = req.reg_off;
-        if offset >= VHOST_USER_CONFIG_OFFSET as u64 {
+        let ret = if offset >= VHOST_USER_CONFIG_OFFSET as u64 {
             offset -= VHOST_USER_CONFIG_OFFSET as u64;
             let gdev = &mut dev.gdev.lock().unwrap();
 
             match req.op {
                 BAO_IO_READ => self.config_read(req, gdev, offset),
-                BAO_IO_WRITE => self.config_write(req, gdev, offset),
+                BAO_IO_WRITE => {
+                    let ret = self.config_write(req, gdev, offset);
+                    // The driver changed the configuration space
+                    dev.bump_config_generation();
+                    ret
+                }
                 _ => Err(Error::InvalidMmioDir(req.op as u8)),
             }
         } else {
@@ -527,41 +1529,37 @@ impl BaoMmio {
                 BAO_IO_WRITE => self.io_write(req, dev, offset),
                 _ => Err(Error::InvalidMmioDir(req.op as u8)),
             }
-        }
+        };
+
+        trace!(
+            target: &self.target,
+            "{} reg={} offset=0x{:x} width={} value=0x{:x} vcpu={}",
+            if req.op == BAO_IO_WRITE { "write" } else { "read" },
+            register_name(req.reg_off),
+            req.reg_off,
+            req.access_width,
+            req.value,
+            req.vcpu_id
+        );
+
+        ret
     }
 }
 
 impl Drop for BaoMmio {
     /// Destructor function for BaoMmio.
     fn drop(&mut self) {
-        for (index, vq) in self.vq.iter().enumerate() {
-            let kick = vq.kick.try_clone().unwrap();
-
-            // Create a BaoIoEventFd struct
-            let ioeventfd = BaoIoEventFd {
-                fd: kick.as_raw_fd() as u32,
-                flags: BAO_IOEVENTFD_FLAG_DEASSIGN, // Deassign the eventfd
-                addr: self.addr + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
-                len: 4,
-                reserved: 0,
-                data: index as u64, // Index of the Virtqueue to match with the 'value' field of the 'bao_io_request' struct
-            };
-
-            // Unregister the kick eventfd.
-            self.guest
-                .dm
-                .lock()
-                .unwrap()
-                .create_ioeventfd(ioeventfd)
-                .unwrap();
-        }
+        let _ = self.exit();
     }
 }
 
 #[cfg(test)]
 mod tests {
     // Import the constants from the parent module
+    use super::*;
+    use crate::simulator::BaoSimDeviceModel;
     use std::sync::Arc;
+    use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
     use vm_memory::{Bytes, FileOffset, GuestAddress};
     use vmm_sys_util::tempfile::TempFile;
 
@@ -772,4 +1770,286 @@ mod tests {
             start_addr = GuestAddress(GUEST_ADDR_INIT);
         }
     }
+
+    /// Transport of a device on top of a simulated guest, without backend nor memory region,
+    /// to exercise the register logic.
+    struct TestMmio {
+        mmio: BaoMmio,
+        guest: Arc<BaoGuest>,
+    }
+
+    impl TestMmio {
+        fn new(num_queues: usize, legacy: bool) -> Self {
+            let dm = Arc::new(BaoSimDeviceModel::new());
+            let guest = BaoGuest::with_device_model(0, 0, dm).unwrap();
+            let sizes = vec![256; num_queues];
+            let mmio = BaoMmio::with_queues(&sizes, guest.clone(), 0, "test".to_string(), legacy);
+
+            Self { mmio, guest }
+        }
+    }
+
+    impl Drop for TestMmio {
+        fn drop(&mut self) {
+            let _ = self.guest.exit();
+        }
+    }
+
+    /// The packed vring base holds both indexes, each followed by its wrap counter.
+    #[test]
+    fn packed_vring_base_encoding() {
+        assert_eq!(PACKED_VRING_BASE, 0x8000_8000);
+        assert_eq!(packed_vring_base(0, false, 0, false), 0);
+        assert_eq!(packed_vring_base(5, true, 0, false), 0x0000_8005);
+        assert_eq!(packed_vring_base(0, false, 7, true), 0x8007_0000);
+        assert_eq!(packed_vring_base(0x7fff, false, 0x7fff, false), 0x7fff_7fff);
+        // The indexes do not overflow into the wrap counters
+        assert_eq!(packed_vring_base(0x8001, false, 0x8002, false), 0x0002_0001);
+    }
+
+    /// The split rings of a legacy virtqueue are laid out from its page frame number.
+    #[test]
+    fn layout_legacy_vq() {
+        let mut test = TestMmio::new(2, true);
+        let mmio = &mut test.mmio;
+
+        // Default page size and alignment
+        mmio.vq[0].size = 256;
+        assert!(mmio.layout_legacy_vq(0x10));
+        let vq = &mmio.vq[0];
+        assert_eq!((vq.desc_hi, vq.desc_lo), (0, 0x10000));
+        assert_eq!((vq.avail_hi, vq.avail_lo), (0, 0x11000));
+        assert_eq!((vq.used_hi, vq.used_lo), (0, 0x12000));
+        assert_eq!(vq.pfn, 0x10);
+
+        // Smaller alignment and addresses above 4 GiB
+        mmio.queue_sel = 1;
+        mmio.guest_page_size = 0x1000;
+        mmio.vq[1].size = 8;
+        mmio.vq[1].align = 64;
+        assert!(mmio.layout_legacy_vq(0x100010));
+        let vq = &mmio.vq[1];
+        assert_eq!((vq.desc_hi, vq.desc_lo), (1, 0x10000));
+        assert_eq!((vq.avail_hi, vq.avail_lo), (1, 0x10080));
+        assert_eq!((vq.used_hi, vq.used_lo), (1, 0x100c0));
+    }
+
+    /// A legacy virtqueue is not laid out with an invalid configuration.
+    #[test]
+    fn layout_legacy_vq_rejected() {
+        let mut test = TestMmio::new(1, true);
+        let mmio = &mut test.mmio;
+
+        // No size
+        assert!(!mmio.layout_legacy_vq(0x10));
+
+        // Alignment not a power of two
+        mmio.vq[0].size = 256;
+        mmio.vq[0].align = 3000;
+        assert!(!mmio.layout_legacy_vq(0x10));
+
+        // Page size not a power of two
+        mmio.vq[0].align = LEGACY_PAGE_SIZE;
+        mmio.guest_page_size = 3000;
+        assert!(!mmio.layout_legacy_vq(0x10));
+
+        // Ready virtqueue
+        mmio.guest_page_size = LEGACY_PAGE_SIZE;
+        mmio.vq[0].ready = 1;
+        assert!(!mmio.layout_legacy_vq(0x10));
+
+        // Virtqueue that does not exist
+        mmio.vq[0].ready = 0;
+        mmio.queue_sel = 1;
+        assert!(!mmio.layout_legacy_vq(0x10));
+
+        assert_eq!(mmio.vq[0].pfn, 0);
+        assert_eq!(mmio.vq[0].desc_lo, 0);
+    }
+
+    /// Each initialization step requires the previous one, and bits are never cleared.
+    #[test]
+    fn status_handshake() {
+        let mut test = TestMmio::new(1, false);
+        let mmio = &mut test.mmio;
+        let ack = VIRTIO_CONFIG_S_ACKNOWLEDGE;
+        let driver = ack | VIRTIO_CONFIG_S_DRIVER;
+        let features_ok = driver | VIRTIO_CONFIG_S_FEATURES_OK;
+
+        assert!(mmio.check_status(ack).is_ok());
+        assert!(mmio.check_status(VIRTIO_CONFIG_S_DRIVER).is_err());
+        assert!(mmio
+            .check_status(ack | VIRTIO_CONFIG_S_FEATURES_OK)
+            .is_err());
+        assert!(mmio
+            .check_status(driver | VIRTIO_CONFIG_S_DRIVER_OK)
+            .is_err());
+        assert!(mmio
+            .check_status(features_ok | VIRTIO_CONFIG_S_DRIVER_OK)
+            .is_ok());
+
+        // Bits are only cleared by a device reset, while DEVICE_NEEDS_RESET belongs to the device
+        mmio.status = driver | VIRTIO_CONFIG_S_NEEDS_RESET;
+        assert!(mmio.check_status(ack).is_err());
+        assert!(mmio.check_status(features_ok).is_ok());
+        assert!(mmio.check_status(driver | VIRTIO_CONFIG_S_FAILED).is_ok());
+    }
+
+    /// Legacy devices have no FEATURES_OK.
+    #[test]
+    fn status_handshake_legacy() {
+        let mut test = TestMmio::new(1, true);
+        let mmio = &mut test.mmio;
+        let driver = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+
+        assert!(mmio
+            .check_status(driver | VIRTIO_CONFIG_S_FEATURES_OK)
+            .is_err());
+        assert!(mmio
+            .check_status(driver | VIRTIO_CONFIG_S_DRIVER_OK)
+            .is_ok());
+    }
+
+    /// The driver features are validated on FEATURES_OK.
+    #[test]
+    fn features_ok_validation() {
+        let mut test = TestMmio::new(1, false);
+        let mmio = &mut test.mmio;
+        let transport = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM);
+        let offered = transport | 1;
+        let mut options = DeviceOptions::default();
+        let check = |mmio: &BaoMmio, options: &DeviceOptions| {
+            mmio.check_features(offered, offered, options)
+        };
+
+        mmio.driver_features = transport | 1;
+        assert!(check(mmio, &options).is_ok());
+
+        // Features that were not offered
+        mmio.driver_features = transport | 2;
+        assert!(matches!(
+            check(mmio, &options),
+            Err(FeatureRejection::Violation(_))
+        ));
+
+        // Masked features
+        options.features_mask = 1;
+        mmio.driver_features = transport | 1;
+        assert!(matches!(
+            check(mmio, &options),
+            Err(FeatureRejection::Violation(_))
+        ));
+        options.features_mask = 0;
+
+        // Legacy driver
+        mmio.driver_features = 1 << VIRTIO_F_IOMMU_PLATFORM;
+        assert!(matches!(
+            check(mmio, &options),
+            Err(FeatureRejection::Unsupported(_))
+        ));
+
+        // Declined mandatory feature
+        mmio.driver_features = 1 << VIRTIO_F_VERSION_1;
+        assert!(matches!(
+            check(mmio, &options),
+            Err(FeatureRejection::Unsupported(_))
+        ));
+
+        // Overridden feature the backend does not implement
+        let event_idx = 1 << VIRTIO_RING_F_EVENT_IDX;
+        options.features_override = event_idx;
+        mmio.driver_features = transport | event_idx;
+        assert!(matches!(
+            mmio.check_features(offered | event_idx, offered, &options),
+            Err(FeatureRejection::Unsupported(_))
+        ));
+        assert!(mmio
+            .check_features(offered | event_idx, offered | event_idx, &options)
+            .is_ok());
+    }
+
+    /// Legacy drivers do not negotiate VIRTIO_F_VERSION_1 on legacy devices.
+    #[test]
+    fn features_ok_validation_legacy() {
+        let mut test = TestMmio::new(1, true);
+        let mmio = &mut test.mmio;
+        let mut options = DeviceOptions::default();
+        options.transport_features.forced = 0;
+
+        mmio.driver_features = 1;
+        assert!(mmio.check_features(1, 1, &options).is_ok());
+    }
+
+    /// A queue reset brings the virtqueue registers back to their initial values.
+    #[test]
+    fn queue_reset() {
+        let mut vq = VirtQueue::new(256);
+        vq.ready = 1;
+        vq.size = 128;
+        vq.desc_lo = 0x1000;
+        vq.desc_hi = 1;
+        vq.avail_lo = 0x2000;
+        vq.avail_hi = 1;
+        vq.used_lo = 0x3000;
+        vq.used_hi = 1;
+        vq.align = 64;
+        vq.pfn = 0x10;
+        vq.packed_base = Some(PACKED_VRING_BASE);
+        vq.kick.write(1).unwrap();
+
+        vq.reset();
+        assert_eq!((vq.ready, vq.size, vq.size_max), (0, 0, 256));
+        assert_eq!((vq.desc_lo, vq.desc_hi), (0, 0));
+        assert_eq!((vq.avail_lo, vq.avail_hi), (0, 0));
+        assert_eq!((vq.used_lo, vq.used_hi), (0, 0));
+        assert_eq!((vq.align, vq.pfn), (LEGACY_PAGE_SIZE, 0));
+        assert_eq!(vq.packed_base, None);
+        // The pending kick was discarded
+        assert!(vq.kick.read().is_err());
+    }
+
+    /// Every shared memory region reads as missing, whichever region is selected.
+    #[test]
+    fn shm_select() {
+        let mut test = TestMmio::new(1, false);
+        let mmio = &mut test.mmio;
+
+        for _ in 0..2 {
+            for reg in [
+                VIRTIO_MMIO_SHM_LEN_LOW,
+                VIRTIO_MMIO_SHM_LEN_HIGH,
+                VIRTIO_MMIO_SHM_BASE_LOW,
+                VIRTIO_MMIO_SHM_BASE_HIGH,
+            ] {
+                assert_eq!(mmio.transport_read(reg), Some(u32::MAX));
+            }
+            mmio.queue_sel = 1;
+        }
+    }
+
+    /// The registers of the selected virtqueue read as 0 if it does not exist, and the legacy
+    /// registers only exist on legacy devices.
+    #[test]
+    fn transport_registers() {
+        let mut test = TestMmio::new(1, false);
+        let mmio = &mut test.mmio;
+
+        assert_eq!(
+            mmio.transport_read(VIRTIO_MMIO_MAGIC_VALUE),
+            Some(u32::from_le_bytes(*b"virt"))
+        );
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_VERSION), Some(2));
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_QUEUE_NUM_MAX), Some(256));
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_QUEUE_PFN), None);
+        // Registers involving the device
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_DEVICE_ID), None);
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_INTERRUPT_STATUS), None);
+
+        mmio.queue_sel = 1;
+        assert_eq!(mmio.transport_read(VIRTIO_MMIO_QUEUE_NUM_MAX), Some(0));
+
+        let test = TestMmio::new(1, true);
+        assert_eq!(test.mmio.transport_read(VIRTIO_MMIO_VERSION), Some(1));
+        assert_eq!(test.mmio.transport_read(VIRTIO_MMIO_QUEUE_PFN), Some(0));
+    }
 }
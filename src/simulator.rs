@@ -0,0 +1,468 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Simulator' module contains an in-memory implementation of the `DeviceModel` trait,
+//! which plays the role of the I/O Request Management System of a Bao-enabled kernel.
+//! It allows the guests, devices and MMIO layer of the frontend to be exercised on a plain
+//! Linux machine:
+//!
+//! - I/O requests are scripted by the caller and handed to the guest I/O thread.
+//! - Completed I/O requests are recorded so the caller can inspect the returned values.
+//! - Writes to an address with a registered ioeventfd trigger the eventfd and are completed
+//!   without reaching the guest I/O thread, as the kernel does for `QUEUE_NOTIFY` writes.
+//! - Registered irqfds are kept so the caller can wait for interrupts towards the guest.
+
+use super::devicemodel::DeviceModel;
+use bao_sys::{defines::*, error::*, types::*};
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Internal state of the simulated I/O Request Management System.
+///
+/// # Attributes
+///
+/// * `backend` - Whether the VirtIO backend of the guest exists.
+/// * `client` - Whether the I/O client exists.
+/// * `pending` - I/O requests waiting to be handed to the I/O client.
+/// * `completed` - I/O requests completed by the I/O client.
+/// * `ioeventfds` - Registered ioeventfds along with a duplicate of their eventfd.
+/// * `irqfds` - Registered irqfds along with a duplicate of their eventfd.
+/// * `notifications` - Number of guest notifications issued through `notify_guest`.
+#[derive(Default)]
+struct SimState {
+    backend: bool,
+    client: bool,
+    pending: VecDeque<BaoIoRequest>,
+    completed: VecDeque<BaoIoRequest>,
+    ioeventfds: Vec<(BaoIoEventFd, EventFd)>,
+    irqfds: Vec<(BaoIrqFd, EventFd)>,
+    notifications: u64,
+}
+
+/// Represents a simulated BaoDeviceModel.
+///
+/// # Attributes
+///
+/// * `state` - Mutex-protected state of the simulated I/O Request Management System.
+/// * `cond` - Condition variable signalled whenever a request is queued or completed.
+pub struct BaoSimDeviceModel {
+    state: Mutex<SimState>,
+    cond: Condvar,
+}
+
+impl BaoSimDeviceModel {
+    /// Creates a new BaoSimDeviceModel.
+    ///
+    /// # Return
+    ///
+    /// * `BaoSimDeviceModel` - The BaoSimDeviceModel object.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SimState {
+                backend: true,
+                ..Default::default()
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Builds the error returned by the simulated IOCTLs.
+    ///
+    /// # Arguments
+    ///
+    /// * `errno` - The error number mimicking the one returned by the kernel.
+    ///
+    /// # Return
+    ///
+    /// * `Error` - The error.
+    fn error(errno: i32) -> Error {
+        Error::BaoIoctlError(
+            std::io::Error::from_raw_os_error(errno),
+            std::any::type_name::<Self>(),
+        )
+    }
+
+    /// Duplicates a file descriptor registered by the frontend into an EventFd.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor to be duplicated.
+    ///
+    /// # Return
+    ///
+    /// * `Result<EventFd>` - A Result containing the duplicated EventFd on success.
+    fn dup_eventfd(fd: i32) -> Result<EventFd> {
+        // SAFETY: `dup` does not touch memory and its return value is checked.
+        let fd = unsafe { libc::dup(fd) };
+        if fd < 0 {
+            return Err(Self::error(libc::EBADF));
+        }
+
+        // SAFETY: The file descriptor was just duplicated, so we own it.
+        Ok(unsafe { EventFd::from_raw_fd(fd) })
+    }
+
+    /// Pushes a scripted I/O request.
+    /// Writes matching a registered ioeventfd trigger that eventfd and are completed right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The BaoIoRequest to be pushed.
+    ///
+    /// # Return
+    ///
+    /// * `bool` - True if the request was queued for the I/O client, false if an ioeventfd consumed it.
+    pub fn push_request(&self, req: BaoIoRequest) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        // Check if an ioeventfd is registered for this write access
+        if req.op == BAO_IO_WRITE {
+            let ioeventfd = state.ioeventfds.iter().find(|(ev, _)| {
+                ev.addr == req.addr
+                    && (ev.flags & BAO_IOEVENTFD_FLAG_DATAMATCH == 0 || ev.data == req.value)
+            });
+            if let Some((_, kick)) = ioeventfd {
+                kick.write(1).unwrap();
+                state.completed.push_back(req);
+                self.cond.notify_all();
+                return false;
+            }
+        }
+
+        // Queue the request for the I/O client
+        state.pending.push_back(req);
+        self.cond.notify_all();
+        true
+    }
+
+    /// Waits for the next completed I/O request.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait.
+    ///
+    /// # Return
+    ///
+    /// * `Option<BaoIoRequest>` - The completed BaoIoRequest, or None if the timeout expired.
+    pub fn wait_completion(&self, timeout: Duration) -> Option<BaoIoRequest> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(req) = state.completed.pop_front() {
+                return Some(req);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Pushes a scripted I/O request and waits for its completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The BaoIoRequest to be submitted.
+    /// * `timeout` - Maximum time to wait.
+    ///
+    /// # Return
+    ///
+    /// * `Option<BaoIoRequest>` - The completed BaoIoRequest, or None if the timeout expired.
+    pub fn submit(&self, req: BaoIoRequest, timeout: Duration) -> Option<BaoIoRequest> {
+        self.push_request(req);
+        self.wait_completion(timeout)
+    }
+
+    /// Returns the number of I/O requests not yet handed to the I/O client.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    /// Returns the number of guest notifications issued through `notify_guest`.
+    pub fn notifications(&self) -> u64 {
+        self.state.lock().unwrap().notifications
+    }
+
+    /// Returns the number of registered ioeventfds.
+    pub fn ioeventfds(&self) -> usize {
+        self.state.lock().unwrap().ioeventfds.len()
+    }
+
+    /// Returns duplicates of the registered irqfds, in registration order.
+    pub fn irqfds(&self) -> Vec<EventFd> {
+        self.state
+            .lock()
+            .unwrap()
+            .irqfds
+            .iter()
+            .map(|(_, call)| call.try_clone().unwrap())
+            .collect()
+    }
+
+    /// Returns whether the I/O client exists.
+    pub fn has_io_client(&self) -> bool {
+        self.state.lock().unwrap().client
+    }
+
+    /// Returns whether the VirtIO backend of the guest exists.
+    pub fn has_backend(&self) -> bool {
+        self.state.lock().unwrap().backend
+    }
+}
+
+impl DeviceModel for BaoSimDeviceModel {
+    fn destroy(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.backend {
+            return Err(Self::error(libc::EINVAL));
+        }
+        state.backend = false;
+        state.client = false;
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn create_io_client(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.backend || state.client {
+            return Err(Self::error(libc::EINVAL));
+        }
+        state.client = true;
+        Ok(())
+    }
+
+    fn destroy_io_client(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.client {
+            return Err(Self::error(libc::EINVAL));
+        }
+        state.client = false;
+        // Wake up the I/O client waiting inside `attach_io_client`
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn attach_io_client(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // Wait until there is a request to hand out or the I/O client is destroyed
+        while state.client && state.pending.is_empty() {
+            state = self.cond.wait(state).unwrap();
+        }
+        if !state.client {
+            return Err(Self::error(libc::ENODEV));
+        }
+        Ok(())
+    }
+
+    fn request_io(&self) -> Result<BaoIoRequest> {
+        let mut state = self.state.lock().unwrap();
+        if !state.client {
+            return Err(Self::error(libc::ENODEV));
+        }
+        state
+            .pending
+            .pop_front()
+            .ok_or_else(|| Self::error(libc::EAGAIN))
+    }
+
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.client {
+            return Err(Self::error(libc::ENODEV));
+        }
+        state.completed.push_back(req);
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.state.lock().unwrap().notifications += 1;
+        Ok(())
+    }
+
+    fn create_ioeventfd(&self, ev: BaoIoEventFd) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let position = state
+            .ioeventfds
+            .iter()
+            .position(|(e, _)| e.fd == ev.fd && e.addr == ev.addr && e.data == ev.data);
+
+        if ev.flags & BAO_IOEVENTFD_FLAG_DEASSIGN != 0 {
+            match position {
+                Some(index) => {
+                    state.ioeventfds.remove(index);
+                }
+                None => return Err(Self::error(libc::ENOENT)),
+            }
+        } else {
+            if position.is_some() {
+                return Err(Self::error(libc::EEXIST));
+            }
+            let kick = Self::dup_eventfd(ev.fd as i32)?;
+            state.ioeventfds.push((ev, kick));
+        }
+
+        Ok(())
+    }
+
+    fn create_irqfd(&self, irq: BaoIrqFd) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let position = state.irqfds.iter().position(|(i, _)| i.fd == irq.fd);
+
+        if irq.flags & BAO_IRQFD_FLAG_DEASSIGN != 0 {
+            match position {
+                Some(index) => {
+                    state.irqfds.remove(index);
+                }
+                None => return Err(Self::error(libc::ENOENT)),
+            }
+        } else {
+            if position.is_some() {
+                return Err(Self::error(libc::EEXIST));
+            }
+            let call = Self::dup_eventfd(irq.fd)?;
+            state.irqfds.push((irq, call));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BaoSimDeviceModel {
+    /// Wakes up any I/O client still waiting for requests.
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.client = false;
+        self.cond.notify_all();
+    }
+}
+
+/// Builds an I/O request as the hypervisor would hand it to the frontend.
+///
+/// # Arguments
+///
+/// * `op` - The direction of the access (`BAO_IO_READ` or `BAO_IO_WRITE`).
+/// * `base` - The base address of the device MMIO window.
+/// * `offset` - The offset of the accessed register.
+/// * `value` - The value written (ignored for reads).
+/// * `access_width` - The width of the access in bytes.
+///
+/// # Return
+///
+/// * `BaoIoRequest` - The I/O request.
+pub fn io_request(op: u64, base: u64, offset: u64, value: u64, access_width: u64) -> BaoIoRequest {
+    BaoIoRequest {
+        virtio_id: 0,
+        reg_off: offset,
+        addr: base + offset,
+        op,
+        value,
+        access_width,
+        cpu_id: 0,
+        vcpu_id: 0,
+        ret: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use virtio_bindings::virtio_mmio::{VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY};
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+
+    const DEV_ADDR: u64 = 0xa003e00;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Scripted requests are handed to the I/O client and their completions are recorded.
+    #[test]
+    fn scripted_request_is_completed() {
+        let dm = Arc::new(BaoSimDeviceModel::new());
+        dm.create_io_client().unwrap();
+
+        // Play the role of the guest I/O thread
+        let client = dm.clone();
+        let handle = std::thread::spawn(move || {
+            client.attach_io_client().unwrap();
+            let mut req = client.request_io().unwrap();
+            req.value = 0x74726976;
+            client.notify_io_completed(req).unwrap();
+        });
+
+        let req = io_request(BAO_IO_READ, DEV_ADDR, VIRTIO_MMIO_MAGIC_VALUE as u64, 0, 4);
+        let done = dm.submit(req, TIMEOUT).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(done.addr, DEV_ADDR + VIRTIO_MMIO_MAGIC_VALUE as u64);
+        assert_eq!(done.value, 0x74726976);
+        assert_eq!(dm.pending(), 0);
+    }
+
+    /// Writes to QUEUE_NOTIFY trigger the ioeventfd matching the queue index.
+    #[test]
+    fn queue_notify_triggers_ioeventfd() {
+        let dm = BaoSimDeviceModel::new();
+        let kicks: Vec<EventFd> = (0..2)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect();
+
+        for (index, kick) in kicks.iter().enumerate() {
+            dm.create_ioeventfd(BaoIoEventFd {
+                fd: kick.as_raw_fd() as u32,
+                flags: BAO_IOEVENTFD_FLAG_DATAMATCH,
+                addr: DEV_ADDR + VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+                len: 4,
+                reserved: 0,
+                data: index as u64,
+            })
+            .unwrap();
+        }
+
+        // Kick the second queue
+        let req = io_request(
+            BAO_IO_WRITE,
+            DEV_ADDR,
+            VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+            1,
+            4,
+        );
+        assert!(!dm.push_request(req));
+        assert_eq!(kicks[1].read().unwrap(), 1);
+        assert!(kicks[0].read().is_err());
+        assert_eq!(dm.pending(), 0);
+
+        // A notification for an unknown queue reaches the I/O client
+        let req = io_request(
+            BAO_IO_WRITE,
+            DEV_ADDR,
+            VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+            7,
+            4,
+        );
+        assert!(dm.push_request(req));
+        assert_eq!(dm.pending(), 1);
+    }
+
+    /// Destroying the I/O client wakes up a blocked `attach_io_client`.
+    #[test]
+    fn destroy_io_client_wakes_attach() {
+        let dm = Arc::new(BaoSimDeviceModel::new());
+        dm.create_io_client().unwrap();
+
+        let client = dm.clone();
+        let handle = std::thread::spawn(move || client.attach_io_client());
+
+        std::thread::sleep(Duration::from_millis(50));
+        dm.destroy_io_client().unwrap();
+
+        assert!(handle.join().unwrap().is_err());
+        assert!(!dm.has_io_client());
+    }
+}
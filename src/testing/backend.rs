@@ -0,0 +1,729 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Backend' module implements a stub vhost-user backend on top of the
+//! `vhost-user-backend` feature of the vhost crate. It behaves as a virtio-rng device:
+//! every device-writable descriptor posted by the driver is filled with non-zero bytes
+//! and returned through the used ring, followed by a used buffer notification.
+//! Packed virtqueues are served as well, for single-descriptor buffers.
+
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{fence, AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use super::{wait_fd, GuestRam};
+use libc::{MAP_SHARED, PROT_READ, PROT_WRITE};
+use vhost::vhost_user::message::{
+    VhostUserConfigFlags, VhostUserInflight, VhostUserMemoryRegion, VhostUserProtocolFeatures,
+    VhostUserSingleMemoryRegion, VhostUserVirtioFeatures, VhostUserVringAddrFlags,
+    VhostUserVringState,
+};
+use vhost::vhost_user::{
+    Backend, BackendListener, Error as VhostUserError, Listener, Result as VhostUserResult,
+    VhostUserBackendReqHandlerMut, VhostUserFrontendReqHandler,
+};
+use virtio_bindings::virtio_config::{
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_PACKED, VIRTIO_F_RING_RESET, VIRTIO_F_VERSION_1,
+};
+use virtio_bindings::virtio_ring::{
+    VRING_DESC_F_WRITE, VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED,
+};
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{Bytes, FileOffset, GuestAddress, MmapRegion};
+use vmm_sys_util::eventfd::EventFd;
+
+// Raw implementation for test purposes
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<()>;
+type GuestRegionMmap = vm_memory::GuestRegionMmap<()>;
+
+/// Size of the inflight state of a descriptor.
+const INFLIGHT_DESC_SIZE: u64 = 16;
+
+/// Interval used by the queue workers to check whether they must stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Struct representing the backend view of a vring.
+///
+/// # Attributes
+///
+/// * `size` - Queue size.
+/// * `desc` - Guest address of the descriptor table.
+/// * `avail` - Guest address of the available ring.
+/// * `used` - Guest address of the used ring.
+/// * `base` - Vring base: next available index to be processed for split vrings, or the
+///   available index and wrap counter (bits 0-15) and the used ones (bits 16-31) for packed
+///   vrings.
+/// * `bases` - Vring bases set by the frontend.
+/// * `call` - Used buffer notification eventfd.
+/// * `stop` - Flag used to stop the queue worker.
+/// * `worker` - Queue worker thread, returning the vring base when stopped.
+#[derive(Default)]
+struct Vring {
+    size: u16,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    base: u32,
+    bases: Vec<u32>,
+    call: Option<EventFd>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<u32>>,
+}
+
+impl Vring {
+    /// Stops the queue worker, if any, and saves the vring base.
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.stop.store(true, Ordering::Release);
+            self.base = worker.join().unwrap();
+        }
+    }
+}
+
+/// Struct representing the stub rng backend.
+///
+/// # Attributes
+///
+/// * `features` - Virtio features offered by the backend.
+/// * `protocol_features` - vhost-user protocol features offered by the backend.
+/// * `acked_features` - Virtio features acknowledged by the frontend.
+/// * `acked_protocol_features` - vhost-user protocol features acknowledged by the frontend.
+/// * `mem` - Guest memory described by the frontend memory table.
+/// * `mem_table` - Frontend user address, guest address and size of each memory region.
+/// * `vrings` - The vrings.
+/// * `inflight` - Inflight region handed by the frontend.
+/// * `backend_req` - Backend request channel handed by the frontend.
+/// * `resets` - Number of device resets requested by the frontend.
+/// * `served` - Number of requests served.
+pub struct StubRngBackend {
+    features: u64,
+    protocol_features: VhostUserProtocolFeatures,
+    acked_features: u64,
+    acked_protocol_features: u64,
+    mem: Option<GuestMemoryMmap>,
+    mem_table: Vec<(u64, u64, u64)>,
+    vrings: Vec<Vring>,
+    inflight: Option<File>,
+    backend_req: Option<Backend>,
+    resets: u64,
+    served: Arc<AtomicU64>,
+}
+
+impl StubRngBackend {
+    /// Constructor function for StubRngBackend.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_queues` - The number of queues.
+    /// * `served` - Counter of the requests served.
+    ///
+    /// # Return
+    ///
+    /// * `StubRngBackend` - The StubRngBackend object.
+    fn new(num_queues: usize, served: Arc<AtomicU64>) -> Self {
+        Self {
+            features: (1 << VIRTIO_F_VERSION_1)
+                | (1 << VIRTIO_F_IOMMU_PLATFORM)
+                | (1 << VIRTIO_F_RING_RESET)
+                | (1 << VIRTIO_F_RING_PACKED)
+                | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
+            protocol_features: VhostUserProtocolFeatures::MQ
+                | VhostUserProtocolFeatures::CONFIG
+                | VhostUserProtocolFeatures::REPLY_ACK
+                | VhostUserProtocolFeatures::INFLIGHT_SHMEM
+                | VhostUserProtocolFeatures::BACKEND_REQ
+                | VhostUserProtocolFeatures::RESET_DEVICE
+                | VhostUserProtocolFeatures::BACKEND_SEND_FD,
+            acked_features: 0,
+            acked_protocol_features: 0,
+            mem: None,
+            mem_table: Vec::new(),
+            vrings: (0..num_queues).map(|_| Vring::default()).collect(),
+            inflight: None,
+            backend_req: None,
+            resets: 0,
+            served,
+        }
+    }
+
+    /// Translates a frontend user address into a guest address.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The frontend user address.
+    ///
+    /// # Return
+    ///
+    /// * `VhostUserResult<u64>` - The guest address.
+    fn translate(&self, addr: u64) -> VhostUserResult<u64> {
+        self.mem_table
+            .iter()
+            .find(|(user_addr, _, size)| addr >= *user_addr && addr < *user_addr + *size)
+            .map(|(user_addr, guest_addr, _)| addr - user_addr + guest_addr)
+            .ok_or(VhostUserError::InvalidParam)
+    }
+
+    /// Returns the vring with the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The vring index.
+    ///
+    /// # Return
+    ///
+    /// * `VhostUserResult<&mut Vring>` - The vring.
+    fn vring(&mut self, index: u32) -> VhostUserResult<&mut Vring> {
+        self.vrings
+            .get_mut(index as usize)
+            .ok_or(VhostUserError::InvalidParam)
+    }
+
+    /// Stops every queue worker.
+    fn stop(&mut self) {
+        for vring in self.vrings.iter_mut() {
+            vring.stop();
+        }
+    }
+}
+
+/// Serves the requests posted to a queue until asked to stop.
+///
+/// # Arguments
+///
+/// * `mem` - The guest memory.
+/// * `queue` - The queue.
+/// * `kick` - The kick eventfd.
+/// * `call` - The call eventfd.
+/// * `stop` - The flag used to stop the worker.
+/// * `served` - Counter of the requests served.
+///
+/// # Return
+///
+/// * `u32` - The vring base: the next available index.
+fn serve(
+    mem: GuestMemoryMmap,
+    mut queue: Queue,
+    kick: EventFd,
+    call: EventFd,
+    stop: Arc<AtomicBool>,
+    served: Arc<AtomicU64>,
+) -> u32 {
+    while !stop.load(Ordering::Acquire) {
+        if !wait_fd(kick.as_raw_fd(), POLL_INTERVAL) {
+            continue;
+        }
+        let _ = kick.read();
+
+        while let Some(chain) = queue.pop_descriptor_chain(&mem) {
+            let head = chain.head_index();
+            let mut len = 0;
+            for desc in chain {
+                if desc.is_write_only() {
+                    let bytes: Vec<u8> = (0..desc.len()).map(|i| (i as u8) | 1).collect();
+                    mem.write_slice(&bytes, desc.addr()).unwrap();
+                    len += desc.len();
+                }
+            }
+            queue.add_used(&mem, head, len).unwrap();
+            served.fetch_add(1, Ordering::AcqRel);
+        }
+
+        call.write(1).unwrap();
+    }
+
+    queue.next_avail() as u32
+}
+
+/// Serves the requests posted to a packed queue until asked to stop. Every buffer is made of
+/// a single descriptor, which is marked used in place.
+///
+/// # Arguments
+///
+/// * `mem` - The guest memory.
+/// * `desc` - Guest address of the descriptor ring.
+/// * `size` - Queue size.
+/// * `base` - Vring base: available index and wrap counter (bits 0-15), and used ones
+///   (bits 16-31). A frontend setting the available state only starts the used one from it.
+/// * `kick` - The kick eventfd.
+/// * `call` - The call eventfd.
+/// * `stop` - The flag used to stop the worker.
+/// * `served` - Counter of the requests served.
+///
+/// # Return
+///
+/// * `u32` - The vring base to resume from. As the descriptors are used in place, the used
+///   state is the available one.
+fn serve_packed(
+    mem: GuestMemoryMmap,
+    desc: u64,
+    size: u16,
+    base: u32,
+    kick: EventFd,
+    call: EventFd,
+    stop: Arc<AtomicBool>,
+    served: Arc<AtomicU64>,
+) -> u32 {
+    let (mut next, mut wrap) = ((base & 0x7fff) as u16, base & 0x8000 != 0);
+
+    while !stop.load(Ordering::Acquire) {
+        if !wait_fd(kick.as_raw_fd(), POLL_INTERVAL) {
+            continue;
+        }
+        let _ = kick.read();
+
+        loop {
+            // A descriptor is available when its AVAIL flag matches the wrap counter and its
+            // USED flag does not
+            let addr = desc + 16 * next as u64;
+            let flags: u16 = mem.read_obj(GuestAddress(addr + 14)).unwrap();
+            let avail = flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0;
+            let used = flags & (1 << VRING_PACKED_DESC_F_USED) != 0;
+            if avail != wrap || used == wrap {
+                break;
+            }
+            fence(Ordering::Acquire);
+
+            let buf: u64 = mem.read_obj(GuestAddress(addr)).unwrap();
+            let len: u32 = mem.read_obj(GuestAddress(addr + 8)).unwrap();
+            let mut written = 0;
+            if flags & VRING_DESC_F_WRITE as u16 != 0 {
+                let bytes: Vec<u8> = (0..len).map(|i| (i as u8) | 1).collect();
+                mem.write_slice(&bytes, GuestAddress(buf)).unwrap();
+                written = len;
+            }
+
+            // Mark the descriptor used, keeping its buffer ID
+            mem.write_obj(written, GuestAddress(addr + 8)).unwrap();
+            fence(Ordering::Release);
+            let flags = if wrap {
+                (1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED)
+            } else {
+                0
+            };
+            mem.write_obj(flags as u16, GuestAddress(addr + 14))
+                .unwrap();
+            served.fetch_add(1, Ordering::AcqRel);
+
+            next += 1;
+            if next == size {
+                next = 0;
+                wrap = !wrap;
+            }
+        }
+
+        call.write(1).unwrap();
+    }
+
+    let state = next as u32 | ((wrap as u32) << 15);
+    state | (state << 16)
+}
+
+impl VhostUserBackendReqHandlerMut for StubRngBackend {
+    fn set_owner(&mut self) -> VhostUserResult<()> {
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> VhostUserResult<()> {
+        self.stop();
+        Ok(())
+    }
+
+    fn reset_device(&mut self) -> VhostUserResult<()> {
+        self.stop();
+        self.resets += 1;
+        Ok(())
+    }
+
+    fn get_features(&mut self) -> VhostUserResult<u64> {
+        Ok(self.features)
+    }
+
+    fn set_features(&mut self, features: u64) -> VhostUserResult<()> {
+        self.acked_features = features;
+        Ok(())
+    }
+
+    fn set_mem_table(
+        &mut self,
+        ctx: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> VhostUserResult<()> {
+        let mut regions = Vec::new();
+        self.mem_table.clear();
+
+        for (region, file) in ctx.iter().zip(files) {
+            let mmap = MmapRegion::build(
+                Some(FileOffset::new(file, region.mmap_offset)),
+                region.memory_size as usize,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+            )
+            .map_err(|_| VhostUserError::InvalidParam)?;
+            regions.push(
+                GuestRegionMmap::new(mmap, GuestAddress(region.guest_phys_addr))
+                    .map_err(|_| VhostUserError::InvalidParam)?,
+            );
+            self.mem_table
+                .push((region.user_addr, region.guest_phys_addr, region.memory_size));
+        }
+
+        self.mem =
+            Some(GuestMemoryMmap::from_regions(regions).map_err(|_| VhostUserError::InvalidParam)?);
+        Ok(())
+    }
+
+    fn set_vring_num(&mut self, index: u32, num: u32) -> VhostUserResult<()> {
+        self.vring(index)?.size = num as u16;
+        Ok(())
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        index: u32,
+        _flags: VhostUserVringAddrFlags,
+        descriptor: u64,
+        used: u64,
+        available: u64,
+        _log: u64,
+    ) -> VhostUserResult<()> {
+        let (desc, used, avail) = (
+            self.translate(descriptor)?,
+            self.translate(used)?,
+            self.translate(available)?,
+        );
+        let vring = self.vring(index)?;
+        vring.desc = desc;
+        vring.used = used;
+        vring.avail = avail;
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, index: u32, base: u32) -> VhostUserResult<()> {
+        let vring = self.vring(index)?;
+        vring.base = base;
+        vring.bases.push(base);
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, index: u32) -> VhostUserResult<VhostUserVringState> {
+        let vring = self.vring(index)?;
+        vring.stop();
+        Ok(VhostUserVringState::new(index, vring.base))
+    }
+
+    fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        let mem = self
+            .mem
+            .clone()
+            .ok_or(VhostUserError::InvalidOperation("memory table not set"))?;
+        let served = self.served.clone();
+        let packed = self.acked_features & (1 << VIRTIO_F_RING_PACKED) != 0;
+        let vring = self.vring(index as u32)?;
+        vring.stop();
+
+        let (kick, call) = match (fd, vring.call.as_ref()) {
+            // SAFETY: The file descriptor was received from the frontend, so we own it.
+            (Some(file), Some(call)) => (
+                unsafe { EventFd::from_raw_fd(file.into_raw_fd()) },
+                call.try_clone().unwrap(),
+            ),
+            _ => return Ok(()),
+        };
+
+        vring.stop = Arc::new(AtomicBool::new(false));
+        let stop = vring.stop.clone();
+
+        if packed {
+            let (desc, size, base) = (vring.desc, vring.size, vring.base);
+            vring.worker = Some(
+                Builder::new()
+                    .name(format!("stub vring {}", index))
+                    .spawn(move || serve_packed(mem, desc, size, base, kick, call, stop, served))
+                    .unwrap(),
+            );
+            return Ok(());
+        }
+
+        // Build the queue as the driver laid it out in guest memory
+        let mut queue = Queue::new(vring.size).map_err(|_| VhostUserError::InvalidParam)?;
+        queue.set_size(vring.size);
+        queue.set_desc_table_address(Some(vring.desc as u32), Some((vring.desc >> 32) as u32));
+        queue.set_avail_ring_address(Some(vring.avail as u32), Some((vring.avail >> 32) as u32));
+        queue.set_used_ring_address(Some(vring.used as u32), Some((vring.used >> 32) as u32));
+        queue.set_next_avail(vring.base as u16);
+        queue.set_next_used(vring.base as u16);
+        queue.set_ready(true);
+
+        vring.worker = Some(
+            Builder::new()
+                .name(format!("stub vring {}", index))
+                .spawn(move || serve(mem, queue, kick, call, stop, served))
+                .unwrap(),
+        );
+        Ok(())
+    }
+
+    fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        // SAFETY: The file descriptor was received from the frontend, so we own it.
+        self.vring(index as u32)?.call =
+            fd.map(|file| unsafe { EventFd::from_raw_fd(file.into_raw_fd()) });
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, _index: u8, _fd: Option<File>) -> VhostUserResult<()> {
+        Ok(())
+    }
+
+    fn set_backend_req_fd(&mut self, backend: Backend) {
+        backend.set_reply_ack_flag(
+            self.acked_protocol_features & VhostUserProtocolFeatures::REPLY_ACK.bits() != 0,
+        );
+        self.backend_req = Some(backend);
+    }
+
+    fn get_protocol_features(&mut self) -> VhostUserResult<VhostUserProtocolFeatures> {
+        Ok(self.protocol_features)
+    }
+
+    fn set_protocol_features(&mut self, features: u64) -> VhostUserResult<()> {
+        self.acked_protocol_features = features;
+        Ok(())
+    }
+
+    fn get_queue_num(&mut self) -> VhostUserResult<u64> {
+        Ok(self.vrings.len() as u64)
+    }
+
+    fn set_vring_enable(&mut self, index: u32, _enable: bool) -> VhostUserResult<()> {
+        self.vring(index)?;
+        Ok(())
+    }
+
+    fn get_config(
+        &mut self,
+        _offset: u32,
+        size: u32,
+        _flags: VhostUserConfigFlags,
+    ) -> VhostUserResult<Vec<u8>> {
+        // The virtio-rng device has no configuration space
+        Ok(vec![0; size as usize])
+    }
+
+    fn set_config(
+        &mut self,
+        _offset: u32,
+        _buf: &[u8],
+        _flags: VhostUserConfigFlags,
+    ) -> VhostUserResult<()> {
+        Ok(())
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        inflight: &VhostUserInflight,
+    ) -> VhostUserResult<(VhostUserInflight, File)> {
+        // The stub does not track the requests in flight, it only allocates the region
+        let size = inflight.num_queues as u64 * inflight.queue_size as u64 * INFLIGHT_DESC_SIZE;
+        let file = GuestRam::new(size).file;
+
+        let region = VhostUserInflight {
+            mmap_size: size,
+            mmap_offset: 0,
+            num_queues: inflight.num_queues,
+            queue_size: inflight.queue_size,
+        };
+        Ok((region, file))
+    }
+
+    fn set_inflight_fd(
+        &mut self,
+        _inflight: &VhostUserInflight,
+        file: File,
+    ) -> VhostUserResult<()> {
+        self.inflight = Some(file);
+        Ok(())
+    }
+
+    fn get_max_mem_slots(&mut self) -> VhostUserResult<u64> {
+        Ok(1)
+    }
+
+    fn add_mem_region(
+        &mut self,
+        _region: &VhostUserSingleMemoryRegion,
+        _fd: File,
+    ) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation(
+            "memory slots not supported",
+        ))
+    }
+
+    fn remove_mem_region(&mut self, _region: &VhostUserSingleMemoryRegion) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation(
+            "memory slots not supported",
+        ))
+    }
+}
+
+/// Struct representing a running stub backend.
+///
+/// # Attributes
+///
+/// * `backend` - The backend shared with the request handling thread.
+/// * `path` - The vhost-user socket path.
+/// * `served` - Counter of the requests served.
+/// * `conn` - The socket of the frontend connection, or -1 until the frontend connects.
+/// * `handle` - The request handling thread.
+pub struct StubBackend {
+    pub backend: Arc<Mutex<StubRngBackend>>,
+    path: String,
+    served: Arc<AtomicU64>,
+    conn: Arc<AtomicI32>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StubBackend {
+    /// Listens on the given socket and serves a single frontend connection in a dedicated thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The vhost-user socket path.
+    /// * `num_queues` - The number of queues.
+    ///
+    /// # Return
+    ///
+    /// * `StubBackend` - The StubBackend object.
+    pub fn spawn(path: &str, num_queues: usize) -> Self {
+        let served = Arc::new(AtomicU64::new(0));
+        let backend = Arc::new(Mutex::new(StubRngBackend::new(num_queues, served.clone())));
+
+        // Listen before the frontend tries to connect
+        let listener = Listener::new(path, true).unwrap();
+        let mut backend_listener = BackendListener::new(listener, backend.clone()).unwrap();
+
+        let stub = backend.clone();
+        let conn = Arc::new(AtomicI32::new(-1));
+        let handler_conn = conn.clone();
+        let handle = Builder::new()
+            .name("stub backend".to_string())
+            .spawn(move || {
+                let mut handler = loop {
+                    match backend_listener.accept() {
+                        Ok(Some(handler)) => break handler,
+                        Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                        Err(_) => return,
+                    }
+                };
+                handler_conn.store(handler.as_raw_fd(), Ordering::Release);
+
+                // Serve the frontend until it hangs up
+                while handler.handle_request().is_ok() {}
+                stub.lock().unwrap().stop();
+            })
+            .unwrap();
+
+        Self {
+            backend,
+            path: path.to_string(),
+            served,
+            conn,
+            handle: Some(handle),
+        }
+    }
+
+    /// Simulates a crash of the backend by hanging up on the frontend, without waiting for
+    /// the frontend to notice it.
+    pub fn crash(&mut self) {
+        let conn = self.conn.load(Ordering::Acquire);
+        if conn >= 0 {
+            // SAFETY: The socket is owned by the request handling thread, which only closes it
+            // once it stops serving requests.
+            unsafe { libc::shutdown(conn, libc::SHUT_RDWR) };
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Returns the vhost-user socket path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the number of requests served.
+    pub fn served(&self) -> u64 {
+        self.served.load(Ordering::Acquire)
+    }
+
+    /// Replaces the virtio features offered by the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The virtio features.
+    pub fn set_features(&self, features: u64) {
+        self.backend.lock().unwrap().features = features;
+    }
+
+    /// Returns the virtio features acknowledged by the frontend.
+    pub fn acked_features(&self) -> u64 {
+        self.backend.lock().unwrap().acked_features
+    }
+
+    /// Returns the vhost-user protocol features acknowledged by the frontend.
+    pub fn acked_protocol_features(&self) -> u64 {
+        self.backend.lock().unwrap().acked_protocol_features
+    }
+
+    /// Returns the vring bases set by the frontend on a vring.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the vring.
+    pub fn vring_bases(&self, index: usize) -> Vec<u32> {
+        self.backend.lock().unwrap().vrings[index].bases.clone()
+    }
+
+    /// Returns the number of device resets requested by the frontend.
+    pub fn resets(&self) -> u64 {
+        self.backend.lock().unwrap().resets
+    }
+
+    /// Notifies the frontend of a configuration change through the backend request channel.
+    ///
+    /// # Return
+    ///
+    /// * `bool` - True if the frontend acknowledged the notification.
+    pub fn config_change(&self) -> bool {
+        // Do not hold the backend while waiting for the frontend
+        let backend_req = self.backend.lock().unwrap().backend_req.clone();
+        backend_req.is_some_and(|backend_req| backend_req.handle_config_change().is_ok())
+    }
+
+    /// Returns the inode of the inflight region handed by the frontend, if any.
+    pub fn inflight_inode(&self) -> Option<u64> {
+        self.backend
+            .lock()
+            .unwrap()
+            .inflight
+            .as_ref()
+            .map(|file| file.metadata().unwrap().ino())
+    }
+}
+
+impl Drop for StubBackend {
+    /// Stops the queue workers. The request handling thread exits once the frontend hangs up.
+    fn drop(&mut self) {
+        self.backend.lock().unwrap().stop();
+        if let Some(handle) = self.handle.take() {
+            if handle.is_finished() {
+                handle.join().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,555 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Driver' module plays the role of the Linux virtio-mmio guest driver.
+//! Every register access is handed to the frontend through the simulated device model,
+//! so it goes through the guest I/O thread and `BaoMmio::io_event` exactly as a trapped
+//! guest access would. The split and packed virtqueues are placed in the shared guest RAM.
+
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{wait_fd, GuestRam, TIMEOUT};
+use crate::simulator::{io_request, BaoSimDeviceModel};
+use bao_sys::defines::*;
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FEATURES_OK,
+};
+use virtio_bindings::virtio_mmio::{
+    VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL, VIRTIO_MMIO_DEVICE_ID,
+    VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_INTERRUPT_ACK,
+    VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_ALIGN,
+    VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH,
+    VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_READY,
+    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
+    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VERSION,
+};
+use virtio_bindings::virtio_ring::{
+    VRING_DESC_F_WRITE, VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED,
+};
+use vm_memory::{Bytes, FileOffset, GuestAddress};
+use vmm_sys_util::eventfd::EventFd;
+
+// Raw implementation for test purposes
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<()>;
+
+/// Guest address where the driver starts allocating virtqueues.
+const VRING_BASE: u64 = 0x1000;
+/// Guest page size written to the legacy GuestPageSize register.
+pub const LEGACY_PAGE_SIZE: u64 = 0x1000;
+
+/// Struct representing a virtqueue owned by the driver.
+///
+/// # Attributes
+///
+/// * `index` - Queue index.
+/// * `size` - Queue size.
+/// * `desc` - Guest address of the descriptor table (descriptor ring if packed).
+/// * `avail` - Guest address of the available ring (driver area if packed).
+/// * `used` - Guest address of the used ring (device area if packed).
+/// * `next_desc` - Next free descriptor.
+/// * `avail_idx` - Next available ring index.
+/// * `last_used` - Last used ring index consumed by the driver.
+pub struct DriverQueue {
+    pub index: u16,
+    pub size: u16,
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    next_desc: u16,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+/// Struct representing the virtio-mmio guest driver.
+///
+/// # Attributes
+///
+/// * `dm` - The simulated device model used to issue the register accesses.
+/// * `base` - MMIO base address of the device.
+/// * `mem` - Driver view of the guest RAM.
+/// * `next_addr` - Next free guest address for virtqueue allocation.
+pub struct VirtioMmioDriver {
+    dm: Arc<BaoSimDeviceModel>,
+    base: u64,
+    mem: GuestMemoryMmap,
+    next_addr: u64,
+}
+
+impl VirtioMmioDriver {
+    /// Constructor function for VirtioMmioDriver.
+    ///
+    /// # Arguments
+    ///
+    /// * `dm` - The simulated device model.
+    /// * `base` - MMIO base address of the device.
+    /// * `ram` - The guest RAM.
+    ///
+    /// # Return
+    ///
+    /// * `VirtioMmioDriver` - The VirtioMmioDriver object.
+    pub fn new(dm: Arc<BaoSimDeviceModel>, base: u64, ram: &GuestRam) -> Self {
+        let mem = GuestMemoryMmap::from_ranges_with_files(&[(
+            GuestAddress(super::RAM_ADDR),
+            ram.size as usize,
+            Some(FileOffset::new(ram.file.try_clone().unwrap(), 0)),
+        )])
+        .unwrap();
+
+        Self {
+            dm,
+            base,
+            mem,
+            next_addr: VRING_BASE,
+        }
+    }
+
+    /// Reads a 32-bit device register.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register.
+    ///
+    /// # Return
+    ///
+    /// * `u32` - The register value.
+    pub fn read(&self, offset: u32) -> u32 {
+        self.read_sized(offset, 4) as u32
+    }
+
+    /// Reads a device register with the given access width.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register.
+    /// * `access_width` - Width of the access in bytes.
+    ///
+    /// # Return
+    ///
+    /// * `u64` - The register value.
+    pub fn read_sized(&self, offset: u32, access_width: u64) -> u64 {
+        let req = io_request(BAO_IO_READ, self.base, offset as u64, 0, access_width);
+        self.dm
+            .submit(req, TIMEOUT)
+            .expect("MMIO read timed out")
+            .value
+    }
+
+    /// Writes a 32-bit device register.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register.
+    /// * `value` - Value to be written.
+    pub fn write(&self, offset: u32, value: u32) {
+        self.write_sized(offset, value as u64, 4);
+    }
+
+    /// Writes a device register with the given access width.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register.
+    /// * `value` - Value to be written.
+    /// * `access_width` - Width of the access in bytes.
+    pub fn write_sized(&self, offset: u32, value: u64, access_width: u64) {
+        let req = io_request(BAO_IO_WRITE, self.base, offset as u64, value, access_width);
+        self.dm.submit(req, TIMEOUT).expect("MMIO write timed out");
+    }
+
+    /// Sets bits in the device status register.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits` - Status bits to be set.
+    pub fn set_status(&self, bits: u32) {
+        let status = self.read(VIRTIO_MMIO_STATUS);
+        self.write(VIRTIO_MMIO_STATUS, status | bits);
+    }
+
+    /// Probes the device as `virtio_mmio_probe` does.
+    ///
+    /// # Return
+    ///
+    /// * `u32` - The virtio device ID.
+    pub fn probe(&self) -> u32 {
+        assert_eq!(
+            self.read(VIRTIO_MMIO_MAGIC_VALUE),
+            u32::from_le_bytes(*b"virt")
+        );
+        assert_eq!(self.read(VIRTIO_MMIO_VERSION), 2);
+        self.read(VIRTIO_MMIO_DEVICE_ID)
+    }
+
+    /// Reads both banks of the device features.
+    ///
+    /// # Return
+    ///
+    /// * `u64` - The device features.
+    pub fn device_features(&self) -> u64 {
+        self.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        let high = self.read(VIRTIO_MMIO_DEVICE_FEATURES) as u64;
+        self.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0);
+        let low = self.read(VIRTIO_MMIO_DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    /// Writes both banks of the driver features, high bank first as Linux does.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The driver features.
+    pub fn write_driver_features(&self, features: u64) {
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES, (features >> 32) as u32);
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+        self.write(VIRTIO_MMIO_DRIVER_FEATURES, features as u32);
+    }
+
+    /// Resets the device and performs the feature negotiation handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `driver_features` - The features supported by the driver.
+    ///
+    /// # Return
+    ///
+    /// * `u64` - The negotiated features.
+    pub fn init(&mut self, driver_features: u64) -> u64 {
+        self.write(VIRTIO_MMIO_STATUS, 0);
+        self.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        self.set_status(VIRTIO_CONFIG_S_DRIVER);
+
+        let features = self.device_features() & driver_features;
+        self.write_driver_features(features);
+
+        self.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_ne!(
+            self.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0,
+            "device rejected the features"
+        );
+
+        features
+    }
+
+    /// Allocates guest memory for the virtqueues.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Size of the allocation.
+    /// * `align` - Alignment of the allocation.
+    ///
+    /// # Return
+    ///
+    /// * `u64` - The guest address of the allocation.
+    fn alloc(&mut self, size: u64, align: u64) -> u64 {
+        let addr = (self.next_addr + align - 1) & !(align - 1);
+        self.next_addr = addr + size;
+        addr
+    }
+
+    /// Sets up a split virtqueue and makes it ready.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Queue index.
+    /// * `size` - Queue size.
+    ///
+    /// # Return
+    ///
+    /// * `DriverQueue` - The virtqueue.
+    pub fn setup_queue(&mut self, index: u16, size: u16) -> DriverQueue {
+        self.write(VIRTIO_MMIO_QUEUE_SEL, index as u32);
+        assert_eq!(self.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        assert!(size as u32 <= self.read(VIRTIO_MMIO_QUEUE_NUM_MAX));
+        self.write(VIRTIO_MMIO_QUEUE_NUM, size as u32);
+
+        let desc = self.alloc(16 * size as u64, 16);
+        let avail = self.alloc(6 + 2 * size as u64, 2);
+        let used = self.alloc(6 + 8 * size as u64, 4);
+
+        self.write(VIRTIO_MMIO_QUEUE_DESC_LOW, desc as u32);
+        self.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_AVAIL_LOW, avail as u32);
+        self.write(VIRTIO_MMIO_QUEUE_AVAIL_HIGH, (avail >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_USED_LOW, used as u32);
+        self.write(VIRTIO_MMIO_QUEUE_USED_HIGH, (used >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+
+        DriverQueue {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            next_desc: 0,
+            avail_idx: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Sets up a legacy virtqueue, laid out contiguously from a page as `vring_init` does,
+    /// which makes it ready.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Queue index.
+    /// * `size` - Queue size.
+    ///
+    /// # Return
+    ///
+    /// * `DriverQueue` - The virtqueue.
+    pub fn setup_legacy_queue(&mut self, index: u16, size: u16) -> DriverQueue {
+        self.write(VIRTIO_MMIO_QUEUE_SEL, index as u32);
+        assert_eq!(self.read(VIRTIO_MMIO_QUEUE_PFN), 0);
+        assert!(size as u32 <= self.read(VIRTIO_MMIO_QUEUE_NUM_MAX));
+        self.write(VIRTIO_MMIO_QUEUE_NUM, size as u32);
+
+        // The used ring starts at the page following the available ring
+        let used_offset = (16 * size as u64 + 6 + 2 * size as u64 + LEGACY_PAGE_SIZE - 1)
+            & !(LEGACY_PAGE_SIZE - 1);
+        let desc = self.alloc(used_offset + 6 + 8 * size as u64, LEGACY_PAGE_SIZE);
+
+        self.write(VIRTIO_MMIO_QUEUE_ALIGN, LEGACY_PAGE_SIZE as u32);
+        self.write(VIRTIO_MMIO_QUEUE_PFN, (desc / LEGACY_PAGE_SIZE) as u32);
+
+        DriverQueue {
+            index,
+            size,
+            desc,
+            avail: desc + 16 * size as u64,
+            used: desc + used_offset,
+            next_desc: 0,
+            avail_idx: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Sets up a packed virtqueue and makes it ready.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Queue index.
+    /// * `size` - Queue size.
+    ///
+    /// # Return
+    ///
+    /// * `DriverQueue` - The virtqueue.
+    pub fn setup_packed_queue(&mut self, index: u16, size: u16) -> DriverQueue {
+        self.write(VIRTIO_MMIO_QUEUE_SEL, index as u32);
+        assert_eq!(self.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        assert!(size as u32 <= self.read(VIRTIO_MMIO_QUEUE_NUM_MAX));
+        self.write(VIRTIO_MMIO_QUEUE_NUM, size as u32);
+
+        let desc = self.alloc(16 * size as u64, 16);
+        let driver = self.alloc(4, 4);
+        let device = self.alloc(4, 4);
+
+        // Start with every descriptor owned by the driver
+        for addr in (desc..desc + 16 * size as u64).step_by(8) {
+            self.mem.write_obj(0u64, GuestAddress(addr)).unwrap();
+        }
+
+        self.write(VIRTIO_MMIO_QUEUE_DESC_LOW, desc as u32);
+        self.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_AVAIL_LOW, driver as u32);
+        self.write(VIRTIO_MMIO_QUEUE_AVAIL_HIGH, (driver >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_USED_LOW, device as u32);
+        self.write(VIRTIO_MMIO_QUEUE_USED_HIGH, (device >> 32) as u32);
+        self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+
+        DriverQueue {
+            index,
+            size,
+            desc,
+            avail: driver,
+            used: device,
+            next_desc: 0,
+            avail_idx: 0,
+            last_used: 0,
+        }
+    }
+
+    /// Sets DRIVER_OK, completing the device initialization.
+    pub fn driver_ok(&self) {
+        self.set_status(VIRTIO_CONFIG_S_DRIVER_OK);
+    }
+
+    /// Posts a single-descriptor buffer to the available ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `vq` - The virtqueue.
+    /// * `addr` - Guest address of the buffer.
+    /// * `len` - Length of the buffer.
+    /// * `write` - Whether the buffer is device-writable.
+    ///
+    /// # Return
+    ///
+    /// * `u16` - The head descriptor index.
+    pub fn add_buffer(&self, vq: &mut DriverQueue, addr: u64, len: u32, write: bool) -> u16 {
+        let head = vq.next_desc;
+        let desc = vq.desc + 16 * head as u64;
+        let flags = if write { VRING_DESC_F_WRITE as u16 } else { 0 };
+
+        // Fill the descriptor
+        self.mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        self.mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        self.mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+        self.mem.write_obj(0u16, GuestAddress(desc + 14)).unwrap();
+
+        // Publish it in the available ring
+        let slot = vq.avail + 4 + 2 * (vq.avail_idx % vq.size) as u64;
+        self.mem.write_obj(head, GuestAddress(slot)).unwrap();
+        fence(Ordering::SeqCst);
+        vq.avail_idx = vq.avail_idx.wrapping_add(1);
+        self.mem
+            .write_obj(vq.avail_idx, GuestAddress(vq.avail + 2))
+            .unwrap();
+
+        vq.next_desc = (vq.next_desc + 1) % vq.size;
+        head
+    }
+
+    /// Posts a single-descriptor buffer to a packed virtqueue.
+    ///
+    /// # Arguments
+    ///
+    /// * `vq` - The virtqueue.
+    /// * `addr` - Guest address of the buffer.
+    /// * `len` - Length of the buffer.
+    /// * `write` - Whether the buffer is device-writable.
+    ///
+    /// # Return
+    ///
+    /// * `u16` - The buffer ID.
+    pub fn add_packed_buffer(&self, vq: &mut DriverQueue, addr: u64, len: u32, write: bool) -> u16 {
+        let id = vq.avail_idx % vq.size;
+        let wrap = (vq.avail_idx / vq.size) % 2 == 0;
+        let desc = vq.desc + 16 * id as u64;
+
+        // Fill the descriptor
+        self.mem.write_obj(addr, GuestAddress(desc)).unwrap();
+        self.mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        self.mem.write_obj(id, GuestAddress(desc + 12)).unwrap();
+
+        // Make it available, flipping AVAIL and USED as the wrap counter goes
+        let mut flags = if write { VRING_DESC_F_WRITE as u16 } else { 0 };
+        flags |= if wrap {
+            1 << VRING_PACKED_DESC_F_AVAIL
+        } else {
+            1 << VRING_PACKED_DESC_F_USED
+        };
+        fence(Ordering::SeqCst);
+        self.mem.write_obj(flags, GuestAddress(desc + 14)).unwrap();
+        vq.avail_idx = vq.avail_idx.wrapping_add(1);
+
+        id
+    }
+
+    /// Consumes the next used descriptor of a packed virtqueue.
+    ///
+    /// # Arguments
+    ///
+    /// * `vq` - The virtqueue.
+    ///
+    /// # Return
+    ///
+    /// * `Option<(u32, u32)>` - The buffer ID and written length, or None if no descriptor was used.
+    pub fn pop_packed_used(&self, vq: &mut DriverQueue) -> Option<(u32, u32)> {
+        let wrap = (vq.last_used / vq.size) % 2 == 0;
+        let desc = vq.desc + 16 * (vq.last_used % vq.size) as u64;
+
+        // A descriptor is used when both AVAIL and USED match the wrap counter
+        fence(Ordering::SeqCst);
+        let flags: u16 = self.mem.read_obj(GuestAddress(desc + 14)).unwrap();
+        if (flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0) != wrap
+            || (flags & (1 << VRING_PACKED_DESC_F_USED) != 0) != wrap
+        {
+            return None;
+        }
+
+        let id: u16 = self.mem.read_obj(GuestAddress(desc + 12)).unwrap();
+        let len: u32 = self.mem.read_obj(GuestAddress(desc + 8)).unwrap();
+        vq.last_used = vq.last_used.wrapping_add(1);
+
+        Some((id as u32, len))
+    }
+
+    /// Notifies the device about new buffers in the virtqueue.
+    ///
+    /// # Arguments
+    ///
+    /// * `vq` - The virtqueue.
+    pub fn kick(&self, vq: &DriverQueue) {
+        self.write(VIRTIO_MMIO_QUEUE_NOTIFY, vq.index as u32);
+    }
+
+    /// Consumes the next entry of the used ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `vq` - The virtqueue.
+    ///
+    /// # Return
+    ///
+    /// * `Option<(u32, u32)>` - The descriptor ID and written length, or None if the ring is empty.
+    pub fn pop_used(&self, vq: &mut DriverQueue) -> Option<(u32, u32)> {
+        fence(Ordering::SeqCst);
+        let used_idx: u16 = self.mem.read_obj(GuestAddress(vq.used + 2)).unwrap();
+        if used_idx == vq.last_used {
+            return None;
+        }
+
+        let elem = vq.used + 4 + 8 * (vq.last_used % vq.size) as u64;
+        let id: u32 = self.mem.read_obj(GuestAddress(elem)).unwrap();
+        let len: u32 = self.mem.read_obj(GuestAddress(elem + 4)).unwrap();
+        vq.last_used = vq.last_used.wrapping_add(1);
+
+        Some((id, len))
+    }
+
+    /// Waits for an interrupt on the irqfd and acknowledges it, as `vm_interrupt` does.
+    ///
+    /// # Arguments
+    ///
+    /// * `irqfd` - The irqfd registered by the device.
+    /// * `timeout` - Maximum time to wait.
+    ///
+    /// # Return
+    ///
+    /// * `Option<u32>` - The acknowledged interrupt status, or None if the timeout expired.
+    pub fn wait_interrupt(&self, irqfd: &EventFd, timeout: Duration) -> Option<u32> {
+        if !wait_fd(irqfd.as_raw_fd(), timeout) {
+            return None;
+        }
+        irqfd.read().unwrap();
+
+        let status = self.read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        self.write(VIRTIO_MMIO_INTERRUPT_ACK, status);
+        Some(status)
+    }
+
+    /// Reads a buffer from the guest RAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address of the buffer.
+    /// * `len` - Length of the buffer.
+    ///
+    /// # Return
+    ///
+    /// * `Vec<u8>` - The buffer contents.
+    pub fn read_buffer(&self, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.mem.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+        buf
+    }
+}
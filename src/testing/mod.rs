@@ -0,0 +1,1145 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The 'Testing' module gathers the pieces needed to exercise the frontend end to end without
+//! a Bao guest:
+//!
+//! - A memfd-backed guest RAM region shared by the frontend, the driver and the backend.
+//! - A virtio-mmio driver that plays the role of the Linux guest driver (see `driver`).
+//! - A stub vhost-user rng backend built on the `vhost-user-backend` feature (see `backend`).
+//!
+//! The I/O Request Management System is provided by the `BaoSimDeviceModel`.
+
+pub mod backend;
+pub mod driver;
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::config::DeviceOptions;
+use super::device::BaoDevice;
+use super::guest::BaoGuest;
+use super::simulator::BaoSimDeviceModel;
+use backend::StubBackend;
+use bao_sys::defines::SUPPORTED_DEVICES;
+use driver::VirtioMmioDriver;
+use vmm_sys_util::tempdir::TempDir;
+
+/// Frontend ID used by the tests.
+pub const FRONTEND_ID: u16 = 0;
+/// Guest ID used by the tests.
+pub const GUEST_ID: u16 = 0;
+/// Device IRQ used by the tests.
+pub const DEV_IRQ: u64 = 0x2f;
+/// Device MMIO base address used by the tests.
+pub const DEV_ADDR: u64 = 0xa003e00;
+/// Guest RAM base address used by the tests.
+pub const RAM_ADDR: u64 = 0x0;
+/// Guest RAM size used by the tests.
+pub const RAM_SIZE: u64 = 0x400000;
+/// Maximum time to wait for any simulated event.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serializes the tests creating devices, since the vhost-user socket names
+/// depend on a process-wide per-device-type index.
+pub static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Index of the vhost-user socket of the next device of each type, as given by the frontend.
+static SOCKET_INDEXES: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+/// Returns the vhost-user socket path the next device of the given type connects to.
+/// The frontend numbers the sockets of the devices of a type in creation order, process-wide
+/// (e.g. `rng.sock0`), so every device addition getting as far as the backend connection must
+/// go through it.
+///
+/// # Arguments
+///
+/// * `dev_id` - The device ID.
+/// * `dir` - The directory holding the vhost-user sockets.
+///
+/// # Return
+///
+/// * `String` - The socket path.
+pub fn next_socket_path(dev_id: u64, dir: &str) -> String {
+    let name = SUPPORTED_DEVICES
+        .iter()
+        .find(|(_, id)| *id as u64 == dev_id)
+        .unwrap()
+        .0;
+
+    let mut indexes = SOCKET_INDEXES.lock().unwrap();
+    let index = indexes.entry(dev_id).or_default();
+    let path = format!("{}{}.sock{}", dir, name, index);
+    *index += 1;
+    path
+}
+
+/// Struct representing a memfd-backed guest RAM region.
+///
+/// # Attributes
+///
+/// * `file` - The memfd backing the guest RAM.
+/// * `size` - The size of the guest RAM.
+pub struct GuestRam {
+    pub file: File,
+    pub size: u64,
+}
+
+impl GuestRam {
+    /// Constructor function for GuestRam.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the guest RAM.
+    ///
+    /// # Return
+    ///
+    /// * `GuestRam` - The GuestRam object.
+    pub fn new(size: u64) -> Self {
+        let name = CString::new("bao-guest-ram").unwrap();
+
+        // SAFETY: The name is a valid C string and the return value is checked.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+        assert!(fd >= 0, "memfd_create failed");
+
+        // SAFETY: The file descriptor was just created, so we own it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size).unwrap();
+
+        Self { file, size }
+    }
+
+    /// Returns a path that can be opened to map the guest RAM, as done with the Bao shared memory driver.
+    pub fn path(&self) -> String {
+        format!("/proc/self/fd/{}", self.file.as_raw_fd())
+    }
+}
+
+/// Waits until a file descriptor becomes readable.
+///
+/// # Arguments
+///
+/// * `fd` - The file descriptor.
+/// * `timeout` - Maximum time to wait.
+///
+/// # Return
+///
+/// * `bool` - True if the file descriptor is readable, false if the timeout expired.
+pub fn wait_fd(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // SAFETY: The pollfd structure is valid for the duration of the call.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) };
+    ret > 0 && (pollfd.revents & libc::POLLIN) != 0
+}
+
+/// Struct representing a complete simulated setup: a guest on top of the simulated device model,
+/// one device connected to a stub vhost-user backend and the virtio-mmio driver in front of it.
+///
+/// # Attributes
+///
+/// * `dm` - The simulated device model.
+/// * `guest` - The guest.
+/// * `dev` - The device.
+/// * `driver` - The virtio-mmio driver.
+/// * `backend` - The stub vhost-user backend.
+/// * `ram` - The guest RAM.
+/// * `dir` - The directory holding the vhost-user socket.
+pub struct TestBed {
+    pub dm: Arc<BaoSimDeviceModel>,
+    pub guest: Arc<BaoGuest>,
+    pub dev: Arc<BaoDevice>,
+    pub driver: VirtioMmioDriver,
+    pub backend: StubBackend,
+    pub ram: GuestRam,
+    pub dir: TempDir,
+}
+
+impl TestBed {
+    /// Creates a guest with a single virtio device of the given type connected to a stub backend.
+    /// The caller must hold `DEVICE_LOCK`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev_id` - The virtio device ID.
+    /// * `num_queues` - The number of queues exposed by the stub backend.
+    ///
+    /// # Return
+    ///
+    /// * `TestBed` - The TestBed object.
+    pub fn new(dev_id: u64, num_queues: usize) -> Self {
+        Self::with_options(dev_id, num_queues, DeviceOptions::default())
+    }
+
+    /// Creates a guest with a single virtio device of the given type and options connected to a stub backend.
+    /// The caller must hold `DEVICE_LOCK`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev_id` - The virtio device ID.
+    /// * `num_queues` - The number of queues exposed by the stub backend.
+    /// * `options` - The device options.
+    ///
+    /// # Return
+    ///
+    /// * `TestBed` - The TestBed object.
+    pub fn with_options(dev_id: u64, num_queues: usize, options: DeviceOptions) -> Self {
+        Self::with_backend(dev_id, num_queues, options, |_| {})
+    }
+
+    /// Creates a guest with a single virtio device of the given type and options connected to a
+    /// stub backend, set up before the device connects to it.
+    /// The caller must hold `DEVICE_LOCK`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev_id` - The virtio device ID.
+    /// * `num_queues` - The number of queues exposed by the stub backend.
+    /// * `options` - The device options.
+    /// * `setup` - The function setting the stub backend up.
+    ///
+    /// # Return
+    ///
+    /// * `TestBed` - The TestBed object.
+    pub fn with_backend(
+        dev_id: u64,
+        num_queues: usize,
+        options: DeviceOptions,
+        setup: impl FnOnce(&StubBackend),
+    ) -> Self {
+        let ram = GuestRam::new(RAM_SIZE);
+        let dir = TempDir::new_with_prefix("/tmp/bao-vhost-frontend").unwrap();
+        let socket_dir = format!("{}/", dir.as_path().display());
+
+        // Start the backend before the device connects to it
+        let backend = StubBackend::spawn(&next_socket_path(dev_id, &socket_dir), num_queues);
+        setup(&backend);
+
+        // Create the guest and the device on top of the simulated device model
+        let dm = Arc::new(BaoSimDeviceModel::new());
+        let guest = BaoGuest::with_device_model(FRONTEND_ID, GUEST_ID, dm.clone()).unwrap();
+        let dev = guest
+            .clone()
+            .add_device(
+                dev_id,
+                DEV_IRQ,
+                DEV_ADDR,
+                RAM_ADDR,
+                RAM_SIZE,
+                ram.path(),
+                socket_dir,
+                options,
+            )
+            .unwrap();
+        guest.enable_io_events();
+
+        let driver = VirtioMmioDriver::new(dm.clone(), DEV_ADDR, &ram);
+
+        Self {
+            dm,
+            guest,
+            dev,
+            driver,
+            backend,
+            ram,
+            dir,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DeviceError;
+    use crate::guest::GuestState;
+    use crate::mmio::VIRTIO_MMIO_QUEUE_RESET;
+    use crate::simulator::io_request;
+    use bao_sys::defines::{BAO_IO_READ, BAO_IO_WRITE};
+    use std::sync::atomic::Ordering;
+    use vhost::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
+    use virtio_bindings::virtio_config::{
+        VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+        VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_CONFIG_S_NEEDS_RESET, VIRTIO_F_IOMMU_PLATFORM,
+        VIRTIO_F_RING_PACKED, VIRTIO_F_RING_RESET, VIRTIO_F_VERSION_1,
+    };
+    use virtio_bindings::virtio_ids::VIRTIO_ID_RNG;
+    use virtio_bindings::virtio_mmio::{
+        VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_GUEST_PAGE_SIZE, VIRTIO_MMIO_INTERRUPT_ACK,
+        VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+        VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+        VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_READY,
+        VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_SHM_BASE_HIGH, VIRTIO_MMIO_SHM_BASE_LOW,
+        VIRTIO_MMIO_SHM_LEN_HIGH, VIRTIO_MMIO_SHM_LEN_LOW, VIRTIO_MMIO_SHM_SEL, VIRTIO_MMIO_STATUS,
+        VIRTIO_MMIO_VERSION,
+    };
+    use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+
+    const BUF_ADDR: u64 = 0x100000;
+    const BUF_LEN: u32 = 64;
+
+    /// The driver probes a virtio-rng device, posts a buffer and receives random bytes from the backend.
+    #[test]
+    fn virtio_rng_round_trip() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+
+        // Probe and initialize the device
+        assert_eq!(tb.driver.probe(), VIRTIO_ID_RNG);
+        let features = tb
+            .driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        assert_ne!(features & (1 << VIRTIO_F_VERSION_1), 0);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+        tb.driver.driver_ok();
+
+        // Post a device-writable buffer and kick the queue
+        tb.driver.add_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(&vq);
+
+        // Wait for the used buffer notification, which the acknowledgement clears
+        let irqfd = tb.dm.irqfds().pop().unwrap();
+        assert_eq!(
+            tb.driver.wait_interrupt(&irqfd, TIMEOUT),
+            Some(VIRTIO_MMIO_INT_VRING)
+        );
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+
+        // The backend filled the whole buffer
+        let (id, len) = tb.driver.pop_used(&mut vq).unwrap();
+        assert_eq!(id, 0);
+        assert_eq!(len, BUF_LEN);
+        assert!(tb
+            .driver
+            .read_buffer(BUF_ADDR, BUF_LEN as usize)
+            .iter()
+            .all(|byte| *byte != 0));
+        assert_eq!(tb.backend.served(), 1);
+    }
+
+    /// Exiting the guest while its I/O thread is parked stops the thread and releases every
+    /// resource registered with the device model.
+    #[test]
+    fn guest_exit_tears_down() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        assert_eq!(tb.dm.ioeventfds(), 1);
+        assert_eq!(tb.dm.irqfds().len(), 1);
+
+        tb.guest.exit().unwrap();
+
+        assert_eq!(tb.guest.state(), GuestState::Stopped);
+        assert!(tb.guest.is_empty());
+        assert_eq!(tb.dm.ioeventfds(), 0);
+        assert!(tb.dm.irqfds().is_empty());
+        assert!(!tb.dm.has_io_client());
+        assert!(!tb.dm.has_backend());
+    }
+
+    /// A paused guest holds off I/O requests until it is resumed.
+    #[test]
+    fn guest_pause_resume() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        assert_eq!(tb.guest.state(), GuestState::Running);
+
+        // Requests are held off while paused
+        assert!(tb.guest.pause());
+        assert!(!tb.guest.pause());
+        let req = io_request(BAO_IO_READ, DEV_ADDR, VIRTIO_MMIO_MAGIC_VALUE as u64, 0, 4);
+        assert!(tb.dm.push_request(req));
+        assert!(tb.dm.wait_completion(Duration::from_millis(200)).is_none());
+
+        // The held off request completes once resumed
+        assert!(tb.guest.resume());
+        let req = tb.dm.wait_completion(TIMEOUT).unwrap();
+        assert_eq!(req.value, u32::from_le_bytes(*b"virt") as u64);
+
+        tb.guest.exit().unwrap();
+        assert_eq!(tb.guest.state(), GuestState::Stopped);
+    }
+
+    /// Faulty accesses are completed as reads-as-zero / writes-ignored without stopping the guest,
+    /// and move the device to DEVICE_NEEDS_RESET when configured so.
+    #[test]
+    fn faulty_access_isolation() {
+        const UNDEFINED_REG: u64 = 0xe0;
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions {
+            needs_reset_on_error: true,
+            ..Default::default()
+        };
+        let tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+
+        // Undefined register of the device
+        let req = io_request(BAO_IO_READ, DEV_ADDR, UNDEFINED_REG, 0xdead, 4);
+        assert_eq!(tb.dm.submit(req, TIMEOUT).unwrap().value, 0);
+        let req = io_request(BAO_IO_WRITE, DEV_ADDR, UNDEFINED_REG, 0xdead, 4);
+        assert!(tb.dm.submit(req, TIMEOUT).is_some());
+
+        // Address outside of any device
+        let req = io_request(BAO_IO_READ, DEV_ADDR + 0x10000, 0, 0xdead, 4);
+        assert_eq!(tb.dm.submit(req, TIMEOUT).unwrap().value, 0);
+
+        assert_eq!(tb.guest.errors(), 3);
+        assert_eq!(tb.dev.errors.load(Ordering::Relaxed), 2);
+
+        // The guest keeps serving the device, which now asks for a reset
+        assert_eq!(tb.guest.state(), GuestState::Running);
+        assert_ne!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+    }
+
+    /// Virtqueues that do not exist have a maximum size of 0, and writing their registers is a
+    /// faulty access.
+    #[test]
+    fn missing_queue_select() {
+        const NUM_QUEUES: usize = 1;
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tb = TestBed::new(VIRTIO_ID_RNG as u64, NUM_QUEUES);
+
+        // Probing the virtqueue past the last one
+        tb.driver.write(VIRTIO_MMIO_QUEUE_SEL, NUM_QUEUES as u32);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+        assert_eq!(tb.guest.errors(), 0);
+
+        // Writes are ignored
+        tb.driver.write(VIRTIO_MMIO_QUEUE_NUM, 16);
+        tb.driver.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        assert_eq!(tb.guest.errors(), 2);
+        assert_eq!(tb.dev.errors.load(Ordering::Relaxed), 2);
+
+        // The guest keeps serving the existing virtqueues
+        assert_eq!(tb.guest.state(), GuestState::Running);
+        tb.driver.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+        assert_ne!(tb.driver.read(VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+    }
+
+    /// Devices whose MMIO window overlaps another device are rejected before connecting to a
+    /// backend, and requests are dispatched according to the window size of each device.
+    #[test]
+    fn device_windows() {
+        const WINDOW: u64 = 0x400;
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions {
+            mmio_size: WINDOW,
+            ..Default::default()
+        };
+        let tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options.clone());
+        let socket_dir = format!("{}/", tb.dir.as_path().display());
+
+        // Duplicate, overlapping from below and overlapping from within
+        for addr in [DEV_ADDR, DEV_ADDR - 0x200, DEV_ADDR + WINDOW - 0x200] {
+            let ret = tb.guest.clone().add_device(
+                VIRTIO_ID_RNG as u64,
+                DEV_IRQ + 1,
+                addr,
+                RAM_ADDR,
+                RAM_SIZE,
+                tb.ram.path(),
+                socket_dir.clone(),
+                options.clone(),
+            );
+            assert!(matches!(
+                ret,
+                Err(DeviceError::OverlappingDevice {
+                    addr: DEV_ADDR,
+                    size: WINDOW
+                })
+            ));
+        }
+        assert_eq!(tb.guest.devices().len(), 1);
+
+        // An access beyond the default window size still reaches the device
+        let req = io_request(BAO_IO_READ, DEV_ADDR, WINDOW - 0x100, 0, 4);
+        assert!(tb.dm.submit(req, TIMEOUT).is_some());
+        assert_eq!(tb.guest.errors(), tb.dev.errors.load(Ordering::Relaxed));
+
+        // An access right after the window reaches no device
+        let req = io_request(BAO_IO_READ, DEV_ADDR, WINDOW, 0, 4);
+        assert!(tb.dm.submit(req, TIMEOUT).is_some());
+        assert_eq!(tb.guest.errors(), tb.dev.errors.load(Ordering::Relaxed) + 1);
+    }
+
+    /// A device addition waiting for a backend that is not started is cancelled when the
+    /// frontend exits, instead of blocking the teardown.
+    #[test]
+    fn device_addition_cancelled() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        let socket_dir = format!("{}/", tb.dir.as_path().display());
+
+        // No backend listens on the socket of the next device
+        let socket = next_socket_path(VIRTIO_ID_RNG as u64, &socket_dir);
+        assert!(!std::path::Path::new(&socket).exists());
+        let guest = tb.guest.clone();
+        let ram_path = tb.ram.path();
+        let adding = std::thread::spawn(move || {
+            guest.add_device(
+                VIRTIO_ID_RNG as u64,
+                DEV_IRQ + 1,
+                DEV_ADDR + 0x1000,
+                RAM_ADDR,
+                RAM_SIZE,
+                ram_path,
+                socket_dir,
+                DeviceOptions::default(),
+            )
+        });
+
+        std::thread::sleep(Duration::from_millis(200));
+        tb.guest.cancel_additions();
+        assert!(matches!(adding.join().unwrap(), Err(DeviceError::Exiting)));
+        assert_eq!(tb.guest.devices().len(), 1);
+    }
+
+    /// Posts a device-writable buffer and waits for the backend to fill it.
+    ///
+    /// # Return
+    ///
+    /// * `Option<(u32, u32)>` - The used buffer ID and length, or None if the timeout expired.
+    fn rng_request(tb: &mut TestBed, vq: &mut driver::DriverQueue) -> Option<(u32, u32)> {
+        tb.driver.add_buffer(vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(vq);
+
+        let irqfd = tb.dm.irqfds().pop().unwrap();
+        tb.driver.wait_interrupt(&irqfd, TIMEOUT)?;
+        tb.driver.pop_used(vq)
+    }
+
+    /// The device is activated on DRIVER_OK with the virtqueues made ready so far, while the
+    /// remaining ones are enabled later on and can be disabled again.
+    #[test]
+    fn late_queue_enable() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        tb.driver.driver_ok();
+        assert_ne!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_DRIVER_OK,
+            0
+        );
+
+        // Enable the queue once the device is running
+        let mut vq = tb.driver.setup_queue(0, 16);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+
+        // Disable the queue and lay it out again
+        tb.driver.write(VIRTIO_MMIO_QUEUE_READY, 0);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+        assert_eq!(tb.backend.served(), 2);
+    }
+
+    /// Status writes breaking the device status handshake are ignored, and FEATURES_OK is only
+    /// kept when the driver accepted offered features.
+    #[test]
+    fn status_handshake() {
+        const FEATURES: u64 = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM);
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+
+        // Steps cannot be skipped
+        tb.driver.write(VIRTIO_MMIO_STATUS, VIRTIO_CONFIG_S_DRIVER);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_STATUS), 0);
+
+        // Features that were not offered are rejected
+        tb.driver.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        tb.driver.set_status(VIRTIO_CONFIG_S_DRIVER);
+        assert_eq!(tb.driver.device_features() & (1 << 60), 0);
+        tb.driver.write_driver_features(FEATURES | (1 << 60));
+        tb.driver.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        // Bits are only cleared by a reset
+        tb.driver
+            .write(VIRTIO_MMIO_STATUS, VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        assert_ne!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_DRIVER,
+            0
+        );
+
+        // The features cannot change once accepted
+        assert_eq!(tb.driver.init(FEATURES), FEATURES);
+        tb.driver.write_driver_features(0);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(tb.backend.acked_features() & FEATURES, FEATURES);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+    }
+
+    /// Once VIRTIO_F_RING_RESET is negotiated, a single virtqueue can be reset and restarted
+    /// while the device keeps running.
+    #[test]
+    fn queue_reset() {
+        const FEATURES: u64 = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM);
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+
+        // Without the feature, the virtqueue is left alone
+        tb.driver.init(FEATURES);
+        tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        tb.driver.write(VIRTIO_MMIO_QUEUE_RESET, 1);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 1);
+
+        let features = FEATURES | (1 << VIRTIO_F_RING_RESET);
+        assert_eq!(tb.driver.init(features), features);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+
+        // Reset the virtqueue and lay it out again
+        tb.driver.write(VIRTIO_MMIO_QUEUE_RESET, 1);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_RESET), 0);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 0);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+        assert_eq!(tb.backend.served(), 2);
+    }
+
+    /// The device has no shared memory region, so every region reads as missing.
+    #[test]
+    fn no_shared_memory_regions() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+
+        for id in [0, 1] {
+            tb.driver.write(VIRTIO_MMIO_SHM_SEL, id);
+            assert_eq!(tb.driver.read(VIRTIO_MMIO_SHM_LEN_LOW), u32::MAX);
+            assert_eq!(tb.driver.read(VIRTIO_MMIO_SHM_LEN_HIGH), u32::MAX);
+            assert_eq!(tb.driver.read(VIRTIO_MMIO_SHM_BASE_LOW), u32::MAX);
+            assert_eq!(tb.driver.read(VIRTIO_MMIO_SHM_BASE_HIGH), u32::MAX);
+        }
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+    }
+
+    /// A device reset stops the backend and clears the driver state, so the device can be
+    /// initialized and activated again any number of times.
+    #[test]
+    fn device_reset() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+
+        for resets in 0..3 {
+            // Initializing the device starts with a reset
+            tb.driver
+                .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+            assert_eq!(tb.backend.resets(), resets);
+            assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_READY), 0);
+
+            let mut vq = tb.driver.setup_queue(0, 16);
+            tb.driver.driver_ok();
+            assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+        }
+
+        // A final reset leaves the device as it was found
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(tb.backend.resets(), 3);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_STATUS), 0);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+    }
+
+    /// The transport features follow the device policy: forced features must be accepted by
+    /// the driver, while offered ones may be declined.
+    #[test]
+    fn transport_features_policy() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options =
+            DeviceOptions::parse("transport_features=iommu_platform:offer+ring_reset:force")
+                .unwrap();
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+        tb.driver.probe();
+
+        let offered = tb.driver.device_features();
+        assert_ne!(offered & (1 << VIRTIO_F_IOMMU_PLATFORM), 0);
+        assert_ne!(offered & (1 << VIRTIO_F_RING_RESET), 0);
+
+        // Declining a forced feature is rejected
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        tb.driver.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        tb.driver.set_status(VIRTIO_CONFIG_S_DRIVER);
+        tb.driver.write_driver_features(1 << VIRTIO_F_VERSION_1);
+        tb.driver.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        // Declining an offered one is not
+        let features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_RING_RESET);
+        assert_eq!(tb.driver.init(features), features);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(
+            tb.backend.acked_features() & (1 << VIRTIO_F_IOMMU_PLATFORM),
+            0
+        );
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+    }
+
+    /// The masked features are hidden from the driver and cannot be negotiated, while the
+    /// overridden ones the backend does not offer are offered to the driver, which can only
+    /// decline them.
+    #[test]
+    fn features_mask_override() {
+        const FEATURES: u64 = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM);
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions::parse(&format!(
+            "features_mask=0x{:x},features_override=0x{:x}",
+            1u64 << VIRTIO_F_RING_RESET,
+            1u64 << VIRTIO_RING_F_EVENT_IDX
+        ))
+        .unwrap();
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+        tb.driver.probe();
+
+        let offered = tb.driver.device_features();
+        assert_eq!(offered & (1 << VIRTIO_F_RING_RESET), 0);
+        assert_ne!(offered & (1 << VIRTIO_RING_F_EVENT_IDX), 0);
+
+        // A masked feature is rejected
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        tb.driver.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        tb.driver.set_status(VIRTIO_CONFIG_S_DRIVER);
+        tb.driver
+            .write_driver_features(FEATURES | (1 << VIRTIO_F_RING_RESET));
+        tb.driver.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        // An overridden feature the backend does not offer is rejected as well
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        tb.driver.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        tb.driver.set_status(VIRTIO_CONFIG_S_DRIVER);
+        tb.driver
+            .write_driver_features(FEATURES | (1 << VIRTIO_RING_F_EVENT_IDX));
+        tb.driver.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        assert_eq!(tb.driver.init(FEATURES), FEATURES);
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(tb.backend.acked_features() & (1 << VIRTIO_F_RING_RESET), 0);
+        assert_eq!(tb.backend.acked_features() & FEATURES, FEATURES);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+    }
+
+    /// Once VIRTIO_F_RING_PACKED is negotiated, the packed vrings are set up on the backend,
+    /// both on activation and when made ready later on.
+    #[test]
+    fn packed_virtqueues() {
+        const FEATURES: u64 = (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_IOMMU_PLATFORM)
+            | (1 << VIRTIO_F_RING_PACKED)
+            | (1 << VIRTIO_F_RING_RESET);
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+        assert_ne!(tb.driver.device_features() & (1 << VIRTIO_F_RING_PACKED), 0);
+
+        assert_eq!(tb.driver.init(FEATURES), FEATURES);
+        let mut vq = tb.driver.setup_packed_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_ne!(tb.backend.acked_features() & (1 << VIRTIO_F_RING_PACKED), 0);
+
+        // Go around the ring, so the wrap counters flip
+        let irqfd = tb.dm.irqfds().pop().unwrap();
+        for _ in 0..20 {
+            let id = tb
+                .driver
+                .add_packed_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+            tb.driver.kick(&vq);
+            tb.driver.wait_interrupt(&irqfd, TIMEOUT).unwrap();
+            assert_eq!(
+                tb.driver.pop_packed_used(&mut vq),
+                Some((id as u32, BUF_LEN))
+            );
+        }
+
+        // Reset the virtqueue and lay it out again
+        tb.driver.write(VIRTIO_MMIO_QUEUE_RESET, 1);
+        let mut vq = tb.driver.setup_packed_queue(0, 16);
+        tb.driver
+            .add_packed_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(&vq);
+        tb.driver.wait_interrupt(&irqfd, TIMEOUT).unwrap();
+        assert_eq!(tb.driver.pop_packed_used(&mut vq), Some((0, BUF_LEN)));
+        assert_eq!(tb.backend.served(), 21);
+    }
+
+    /// A packed vring resumes where the backend stopped it when the driver makes its virtqueue
+    /// ready again, and starts at the beginning of the ring once the virtqueue was reset.
+    #[test]
+    fn packed_vring_base() {
+        const FEATURES: u64 = (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_IOMMU_PLATFORM)
+            | (1 << VIRTIO_F_RING_PACKED)
+            | (1 << VIRTIO_F_RING_RESET);
+        // Index 0 with the wrap counter set
+        const START: u32 = 1 << 15;
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.driver.probe();
+        assert_eq!(tb.driver.init(FEATURES), FEATURES);
+        let mut vq = tb.driver.setup_packed_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(tb.backend.vring_bases(0), vec![START]);
+
+        // Go around the ring, so the wrap counters flip: index 4 with the wrap counter clear
+        let irqfd = tb.dm.irqfds().pop().unwrap();
+        for _ in 0..20 {
+            let id = tb
+                .driver
+                .add_packed_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+            tb.driver.kick(&vq);
+            tb.driver.wait_interrupt(&irqfd, TIMEOUT).unwrap();
+            assert_eq!(
+                tb.driver.pop_packed_used(&mut vq),
+                Some((id as u32, BUF_LEN))
+            );
+        }
+
+        // Made ready again, the vring resumes where it was stopped
+        tb.driver.write(VIRTIO_MMIO_QUEUE_READY, 0);
+        tb.driver.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(tb.backend.vring_bases(0).last(), Some(&4));
+        let id = tb
+            .driver
+            .add_packed_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(&vq);
+        tb.driver.wait_interrupt(&irqfd, TIMEOUT).unwrap();
+        assert_eq!(
+            tb.driver.pop_packed_used(&mut vq),
+            Some((id as u32, BUF_LEN))
+        );
+
+        // Once reset, the vring starts at the beginning of the ring laid out again
+        tb.driver.write(VIRTIO_MMIO_QUEUE_RESET, 1);
+        let mut vq = tb.driver.setup_packed_queue(0, 16);
+        assert_eq!(tb.backend.vring_bases(0).last(), Some(&START));
+        tb.driver
+            .add_packed_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(&vq);
+        tb.driver.wait_interrupt(&irqfd, TIMEOUT).unwrap();
+        assert_eq!(tb.driver.pop_packed_used(&mut vq), Some((0, BUF_LEN)));
+        assert_eq!(tb.backend.served(), 22);
+    }
+
+    /// VIRTIO_F_RING_PACKED is not offered when the backend does not offer it, even if it is
+    /// overridden.
+    #[test]
+    fn packed_virtqueues_unsupported() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions::parse(&format!(
+            "features_override=0x{:x}",
+            1u64 << VIRTIO_F_RING_PACKED
+        ))
+        .unwrap();
+        let tb = TestBed::with_backend(VIRTIO_ID_RNG as u64, 1, options, |backend| {
+            backend.set_features(
+                (1 << VIRTIO_F_VERSION_1)
+                    | (1 << VIRTIO_F_IOMMU_PLATFORM)
+                    | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
+            )
+        });
+
+        tb.driver.probe();
+        assert_eq!(tb.driver.device_features() & (1 << VIRTIO_F_RING_PACKED), 0);
+    }
+
+    /// A legacy device lays its virtqueues out from the page frame numbers written by the
+    /// driver, and negotiates the features once the driver sets DRIVER_OK.
+    #[test]
+    fn legacy_transport() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions::parse("legacy=on").unwrap();
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+
+        // Probe the device as the legacy Linux driver does
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_VERSION), 1);
+        tb.driver
+            .write(VIRTIO_MMIO_GUEST_PAGE_SIZE, driver::LEGACY_PAGE_SIZE as u32);
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        tb.driver.set_status(VIRTIO_CONFIG_S_ACKNOWLEDGE);
+        tb.driver.set_status(VIRTIO_CONFIG_S_DRIVER);
+
+        // No transport feature above bit 31 is offered, and there is no FEATURES_OK
+        assert_eq!(tb.driver.device_features() >> 32, 0);
+        tb.driver.write_driver_features(0);
+        tb.driver.set_status(VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        let mut vq = tb.driver.setup_legacy_queue(0, 16);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_QUEUE_PFN) as u64,
+            vq.desc / driver::LEGACY_PAGE_SIZE
+        );
+        tb.driver.driver_ok();
+        assert_ne!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_DRIVER_OK,
+            0
+        );
+        assert_ne!(tb.backend.acked_features() & (1 << VIRTIO_F_VERSION_1), 0);
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+
+        // Writing 0 to QueuePFN disables the virtqueue
+        tb.driver.write(VIRTIO_MMIO_QUEUE_PFN, 0);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_QUEUE_PFN), 0);
+    }
+
+    /// A device whose backend crashed reconnects to the restarted backend, which resumes
+    /// serving the queue where the previous one stopped with the same inflight region.
+    #[test]
+    fn backend_reconnection() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+        let inflight = tb.backend.inflight_inode();
+        assert!(inflight.is_some());
+
+        // Restart the backend on the same socket
+        let path = tb.backend.path().to_string();
+        tb.backend.crash();
+        tb.backend = StubBackend::spawn(&path, 1);
+
+        let start = std::time::Instant::now();
+        while tb.dev.reconnects.load(Ordering::Relaxed) == 0 {
+            assert!(start.elapsed() < TIMEOUT, "no reconnection");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // The queue resumes on the restarted backend
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((1, BUF_LEN)));
+        assert_eq!(tb.backend.served(), 1);
+
+        // The restarted backend got the inflight region of the previous one
+        assert_eq!(tb.backend.inflight_inode(), inflight);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+    }
+
+    /// A device that is not allowed to reconnect moves to DEVICE_NEEDS_RESET once its backend hangs up.
+    #[test]
+    fn backend_reconnection_disabled() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions {
+            reconnect_retries: 0,
+            ..Default::default()
+        };
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+
+        tb.backend.crash();
+
+        let start = std::time::Instant::now();
+        while tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET == 0 {
+            assert!(
+                start.elapsed() < TIMEOUT,
+                "device not moved to DEVICE_NEEDS_RESET"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(tb.dev.reconnects.load(Ordering::Relaxed), 0);
+    }
+
+    /// Only the protocol features both offered by the backend and allowed by the device
+    /// policy are negotiated.
+    #[test]
+    fn protocol_features_policy() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions::parse("protocol_features=mq+reply_ack").unwrap();
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+
+        let acked = VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK;
+        assert_eq!(tb.dev.protocol_features(), acked);
+        assert_eq!(tb.backend.acked_protocol_features(), acked.bits());
+    }
+
+    /// No protocol feature is negotiated with a backend that does not offer
+    /// VHOST_USER_F_PROTOCOL_FEATURES.
+    #[test]
+    fn protocol_features_unsupported() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+        tb.backend
+            .set_features((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+
+        tb.driver.probe();
+        let features = tb
+            .driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        assert_ne!(features & (1 << VIRTIO_F_VERSION_1), 0);
+
+        assert!(tb.dev.protocol_features().is_empty());
+        assert_eq!(tb.backend.acked_protocol_features(), 0);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+    }
+
+    /// A configuration change notified by the backend raises a configuration change interrupt,
+    /// starts a new configuration generation and the backend gets its reply.
+    #[test]
+    fn backend_config_change() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tb = TestBed::new(VIRTIO_ID_RNG as u64, 1);
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        assert!(tb
+            .dev
+            .protocol_features()
+            .contains(VhostUserProtocolFeatures::BACKEND_REQ));
+        let generation = tb.driver.read(VIRTIO_MMIO_CONFIG_GENERATION);
+
+        assert!(tb.backend.config_change());
+
+        let irqfd = tb.dm.irqfds().pop().unwrap();
+        assert!(wait_fd(irqfd.as_raw_fd(), TIMEOUT));
+        let status = tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        assert_ne!(status & VIRTIO_MMIO_INT_CONFIG, 0);
+
+        // The driver acknowledges the interrupt
+        tb.driver
+            .write(VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_CONFIG);
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS) & VIRTIO_MMIO_INT_CONFIG,
+            0
+        );
+
+        // The configuration space may have changed, and it may again on a device reset
+        let changed = tb.driver.read(VIRTIO_MMIO_CONFIG_GENERATION);
+        assert_ne!(changed, generation);
+        tb.driver.write(VIRTIO_MMIO_STATUS, 0);
+        assert_ne!(tb.driver.read(VIRTIO_MMIO_CONFIG_GENERATION), changed);
+    }
+
+    /// Without an irqfd, used buffer notifications reach the guest through `notify_guest`.
+    #[test]
+    fn interrupt_fallback() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions {
+            irqfd: false,
+            ..Default::default()
+        };
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+        assert!(tb.dm.irqfds().is_empty());
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+
+        tb.driver.add_buffer(&mut vq, BUF_ADDR, BUF_LEN, true);
+        tb.driver.kick(&vq);
+
+        let start = std::time::Instant::now();
+        while tb.dm.notifications() == 0 {
+            assert!(start.elapsed() < TIMEOUT, "no guest notification");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS),
+            VIRTIO_MMIO_INT_VRING
+        );
+        tb.driver
+            .write(VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING);
+        assert_eq!(tb.driver.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+        assert_eq!(tb.driver.pop_used(&mut vq), Some((0, BUF_LEN)));
+    }
+
+    /// Without ioeventfds, QUEUE_NOTIFY writes reach the frontend, which kicks the queue itself.
+    #[test]
+    fn userspace_kick() {
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = DeviceOptions {
+            ioeventfd: false,
+            ..Default::default()
+        };
+        let mut tb = TestBed::with_options(VIRTIO_ID_RNG as u64, 1, options);
+        assert_eq!(tb.dm.ioeventfds(), 0);
+
+        tb.driver.probe();
+        tb.driver
+            .init((1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_F_IOMMU_PLATFORM));
+        let mut vq = tb.driver.setup_queue(0, 16);
+        tb.driver.driver_ok();
+        assert_eq!(rng_request(&mut tb, &mut vq), Some((0, BUF_LEN)));
+
+        // Kicking a queue the device does not have is a faulty access
+        let req = io_request(
+            BAO_IO_WRITE,
+            DEV_ADDR,
+            VIRTIO_MMIO_QUEUE_NOTIFY as u64,
+            1,
+            4,
+        );
+        assert!(tb.dm.submit(req, TIMEOUT).is_some());
+        assert_eq!(tb.dev.errors.load(Ordering::Relaxed), 1);
+    }
+}